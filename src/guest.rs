@@ -19,6 +19,7 @@ pub enum DisasException {
     /// Possible cases:
     /// - jump target (forcing start of a new block)
     /// - translation block size limit exceeded
+    /// - trap to the runtime (e.g. system call)
     ///
     /// Fields:
     /// - `0`: the next PC
@@ -33,6 +34,14 @@ pub enum DisasException {
     /// - `0`: PC for branch taken
     /// - `1`: PC for branch not taken
     Branch(Option<usize>, Option<usize>),
+    /// Instruction fetch from a guest page that is not executable.
+    ///
+    /// An `ACCESS_FAULT` trap is emitted in place of the faulting instruction, so that the fault
+    /// is only raised if the guest actually reaches it.
+    ///
+    /// Fields:
+    /// - `0`: the faulting PC
+    AccessFault(usize),
     /// Unexpected error.
     ///
     /// Possible cases:
@@ -60,6 +69,7 @@ impl Display for DisasException {
                 };
                 write!(f, "branch: direct {}; aux {}", df, af)
             }
+            DisasException::AccessFault(a) => write!(f, "access fault: {:#x}", a),
             DisasException::Unexpected(s) => write!(f, "unexpected: {}", s),
         }
     }
@@ -69,10 +79,14 @@ impl Display for DisasException {
 pub struct TranslationBlock<R: HostStorage> {
     /// Start address in guest view.
     pub start_pc: usize,
+    /// End address (exclusive) in guest view.
+    pub end_pc: usize,
     /// Generated IR operations.
     pub ops: Vec<Op<R>>,
-    direct_chain_idx: Option<usize>, // taken branch
-    aux_chain_idx: Option<usize>,    // not taken branch
+    /// Index of the `LOOKUP_TB` trap in `ops` that ends the block with the taken branch.
+    pub direct_chain_idx: Option<usize>,
+    /// Index of the `LOOKUP_TB` trap in `ops` that ends the block with the branch not taken.
+    pub aux_chain_idx: Option<usize>,
 }
 
 /// Disassembler functions to be invoked from the runtime.
@@ -12,20 +12,92 @@ use crate::util::*;
 use std::collections::HashMap;
 use std::iter::*;
 use std::rc::{Rc, Weak};
+use sysreg::{SysRegStorage, SYSREGS};
 
 type InsnType = u32;
 
+/// Environment variable to enable folding of literal loads.
+///
+/// If set, a PC-relative literal load from a guest page that is not writable is translated into
+/// its value at translation time.  Blocks are not translated again if the guest makes the page
+/// writable and changes the literal later on.
+pub const FOLD_LITERAL_ENV: &str = "KHEMU_FOLD_LITERAL";
+
+/// Size of the data and instruction cache lines reported to the guest.
+///
+/// `DC ZVA` zeroes a block of this size, and `IC IVAU` invalidates the translated blocks that
+/// overlap a line of this size.
+pub const CACHE_LINE_SIZE: u64 = 64;
+
+/// Architectural state of an ARM64 guest CPU.
+///
+/// Emitted blocks access the fixed registers of the frontend in this structure through the
+/// pointer passed to them.
+#[repr(C)]
+#[derive(Debug, Clone, Default)]
+pub struct Arm64CpuState {
+    /// General-purpose registers x0-x30.
+    pub x: [u64; 31],
+    /// Stack pointer.
+    pub sp: u64,
+    /// Program counter, only up to date when a block exits.
+    pub pc: u64,
+    /// Raw bits of the lower 64 bits of the SIMD&FP registers.
+    pub d: [u64; 32],
+    /// Negative flag in bit 31.
+    pub nf: u32,
+    /// Zero flag, set if `zf` is zero.
+    pub zf: u32,
+    /// Carry flag in bit 0.
+    pub cf: u32,
+    /// Overflow flag in bit 31.
+    pub vf: u32,
+    /// Address tagged by the exclusive monitor, `!0` in the Open Access state.
+    pub exclusive_addr: u64,
+    /// Size in bytes of the exclusive load that tagged the address.
+    pub exclusive_size: u64,
+    /// Value returned by the exclusive load, lower 64 bits for pairs of 64-bit registers.
+    pub exclusive_val: u64,
+    /// Upper 64 bits returned by an exclusive load of a pair of 64-bit registers.
+    pub exclusive_high: u64,
+    /// Software thread ID register, `TPIDR_EL0`.
+    pub tpidr_el0: u64,
+    /// Floating-point control register, `FPCR`.
+    pub fpcr: u64,
+    /// Floating-point status register, `FPSR`.
+    pub fpsr: u64,
+}
+
+// byte offset of a register in the CPU state
+macro_rules! state_offset {
+    ($($field:tt)+) => {{
+        let state = Arm64CpuState::default();
+        &state.$($field)+ as *const _ as usize - &state as *const _ as usize
+    }};
+}
+
 /// Disassembler context for the ARM64 frontend.
 pub struct Arm64GuestContext<R: HostStorage> {
     map: GuestMap,
     disas_pos: Option<usize>, // addr for next instruction to be disassembled
+    // translate literal loads from read-only pages into immediates
+    fold_literal: bool,
     // 32 general-purpose registers
     xreg: Vec<Rc<KHVal<R>>>,
+    // lower 64 bits of the 32 SIMD&FP registers
+    vreg: Vec<Rc<KHVal<R>>>,
     // Negative, Zero, Carry, Overflow
     nf: Rc<KHVal<R>>,
     zf: Rc<KHVal<R>>,
     cf: Rc<KHVal<R>>,
     vf: Rc<KHVal<R>>,
+    // exclusive monitor: tagged address, access size, loaded value (low and high)
+    excl_addr: Rc<KHVal<R>>,
+    excl_size: Rc<KHVal<R>>,
+    excl_val: Rc<KHVal<R>>,
+    excl_high: Rc<KHVal<R>>,
+    // system registers stored in the CPU state, by offset
+    sysreg: HashMap<usize, Rc<KHVal<R>>>,
     // emulated PC
     pc: Rc<KHVal<R>>,
     // TB book-keeping
@@ -46,32 +118,92 @@ pub struct Arm64GuestContext<R: HostStorage> {
 impl<R: HostStorage> Arm64GuestContext<R> {
     /// Create a new ARM64 disassembler context.
     ///
-    /// Note that this will create fixed registers (x0-x31, nzcv) for the disassembler, so make sure
-    /// that the host context has been [initialized](../../host/trait.HostContext.html#tymethod.init)
-    /// before calling this method, or the host storage creation for registers will fail.
+    /// Note that this will create fixed registers (x0-x31, d0-d31, nzcv, exclusive monitor, system
+    /// registers) in
+    /// [Arm64CpuState](struct.Arm64CpuState.html) for the disassembler, so make sure that the host
+    /// context has been [initialized](../../host/trait.HostContext.html#tymethod.init) before
+    /// calling this method, or the host storage creation for registers will fail.
     pub fn new(map: GuestMap) -> Self {
         Self {
             map,
             disas_pos: None,
+            fold_literal: std::env::var_os(FOLD_LITERAL_ENV).is_some(),
             xreg: (0..32)
+                .map(|i| {
+                    Rc::new(if i == 31 {
+                        KHVal::named("sp".to_owned(), state_offset!(sp), ValueType::U64)
+                    } else {
+                        KHVal::named(format!("x{:02}", i), state_offset!(x[i]), ValueType::U64)
+                    })
+                })
+                .collect(),
+            vreg: (0..32)
                 .map(|i| {
                     Rc::new(KHVal::named(
-                        if i == 31 {
-                            "sp".to_owned()
-                        } else {
-                            format!("x{:02}", i)
-                        },
-                        ValueType::U64,
+                        format!("d{:02}", i),
+                        state_offset!(d[i]),
+                        ValueType::F64,
                     ))
                 })
                 .collect(),
             // use 32bit to simplify calculation when reading NZCV as a whole
-            nf: Rc::new(KHVal::named("nf".to_owned(), ValueType::U32)),
-            zf: Rc::new(KHVal::named("zf".to_owned(), ValueType::U32)),
-            cf: Rc::new(KHVal::named("cf".to_owned(), ValueType::U32)),
-            vf: Rc::new(KHVal::named("vf".to_owned(), ValueType::U32)),
+            nf: Rc::new(KHVal::named(
+                "nf".to_owned(),
+                state_offset!(nf),
+                ValueType::U32,
+            )),
+            zf: Rc::new(KHVal::named(
+                "zf".to_owned(),
+                state_offset!(zf),
+                ValueType::U32,
+            )),
+            cf: Rc::new(KHVal::named(
+                "cf".to_owned(),
+                state_offset!(cf),
+                ValueType::U32,
+            )),
+            vf: Rc::new(KHVal::named(
+                "vf".to_owned(),
+                state_offset!(vf),
+                ValueType::U32,
+            )),
+            excl_addr: Rc::new(KHVal::named(
+                "excl_addr".to_owned(),
+                state_offset!(exclusive_addr),
+                ValueType::U64,
+            )),
+            excl_size: Rc::new(KHVal::named(
+                "excl_size".to_owned(),
+                state_offset!(exclusive_size),
+                ValueType::U64,
+            )),
+            excl_val: Rc::new(KHVal::named(
+                "excl_val".to_owned(),
+                state_offset!(exclusive_val),
+                ValueType::U64,
+            )),
+            excl_high: Rc::new(KHVal::named(
+                "excl_high".to_owned(),
+                state_offset!(exclusive_high),
+                ValueType::U64,
+            )),
+            sysreg: SYSREGS
+                .iter()
+                .filter_map(|r| match r.storage {
+                    SysRegStorage::State(offset) => {
+                        let offset = offset();
+                        let name = r.name.to_lowercase();
+                        Some((offset, Rc::new(KHVal::named(name, offset, ValueType::U64))))
+                    }
+                    _ => None,
+                })
+                .collect(),
             // 64bit simulated PC
-            pc: Rc::new(KHVal::named("pc".to_owned(), ValueType::U64)),
+            pc: Rc::new(KHVal::named(
+                "pc".to_owned(),
+                state_offset!(pc),
+                ValueType::U64,
+            )),
             start_pc: None,
             ops: Vec::new(),
             targets: Vec::new(),
@@ -97,11 +229,15 @@ impl<R: HostStorage> Arm64GuestContext<R> {
     /// Fetch a fixed register, excluding the SP register.
     ///
     /// The SP register encoding per ARM64 specification is the same as hardwired zero in some
-    /// contexts.
+    /// contexts.  A fresh temporary holding zero is returned each time, so that writes to it are
+    /// discarded.
     pub fn reg(&mut self, r: usize) -> Rc<KHVal<R>> {
         assert!(r < 32);
         if r == 31 {
-            self.alloc_u64(0)
+            let ret = self.alloc_val(ValueType::U64);
+            let zero = self.alloc_u64(0);
+            Op::push_mov(self, &ret, &zero);
+            ret
         } else {
             Rc::clone(&self.xreg[r])
         }
@@ -113,6 +249,12 @@ impl<R: HostStorage> Arm64GuestContext<R> {
         Rc::clone(&self.xreg[r])
     }
 
+    /// Fetch the lower 64 bits of a SIMD&FP register.
+    pub fn vreg(&self, r: usize) -> Rc<KHVal<R>> {
+        assert!(r < 32);
+        Rc::clone(&self.vreg[r])
+    }
+
     fn set_direct_chain(&mut self) {
         if let Some(_) = self.direct_chain_idx {
             panic!("direct chain set twice in a single translation block")
@@ -197,14 +339,22 @@ impl<R: HostStorage> Disassembler<R> for Arm64GuestContext<R> {
             if self.ops.len() >= tb_size {
                 // TB size exceeded limit, starting new one
                 let next = self.alloc_u64(pc as u64);
-                Op::push_trap(self, TrapOp::LOOKUP_TB, &next);
+                do_end_tb_to_addr(self, &next, false);
                 return DisasException::Continue(pc);
             } else {
                 // check if instruction is start of other TB
                 if pc != start_pos && self.targets.contains(&pc) {
                     // jump target of some other TBs, terminate this here
+                    let next = self.alloc_u64(pc as u64);
+                    do_end_tb_to_addr(self, &next, false);
                     return DisasException::Continue(pc);
                 }
+                if !self.map.borrow().check(pc as u64, 4, Perm::EXEC) {
+                    // do not translate from non-executable memory
+                    let addr = self.alloc_u64(pc as u64);
+                    Op::push_trap(self, TrapOp::ACCESS_FAULT, &addr, &addr);
+                    return DisasException::AccessFault(pc);
+                }
                 let insn = self.next_insn();
                 if let Err(e) = disas_single(self, insn) {
                     // record the branch targets to break TBs
@@ -228,6 +378,7 @@ impl<R: HostStorage> Disassembler<R> for Arm64GuestContext<R> {
 
         let ret = TranslationBlock {
             start_pc: self.start_pc.unwrap(),
+            end_pc: self.disas_pos.unwrap(),
             ops: ret,
             direct_chain_idx: self.direct_chain_idx,
             aux_chain_idx: self.aux_chain_idx,
@@ -252,7 +403,8 @@ fn unallocated<R: HostStorage>(
     insn: InsnType,
 ) -> Result<(), DisasException> {
     // Emit trap to runtime with UNDEF cause and emulated PC value
-    Op::push_trap(ctx, TrapOp::UNDEF_OPCODE, &Rc::clone(&ctx.pc));
+    let pc = ctx.alloc_u64(ctx.curr_pc() as u64);
+    Op::push_trap(ctx, TrapOp::UNDEF_OPCODE, &pc, &pc);
 
     Ok(())
 }
@@ -336,6 +488,7 @@ disas_subcategory!(ldst, 24, 6,
 );
 
 use data_proc_simd_fp::disas_data_proc_simd_fp;
+use facility::do_end_tb_to_addr;
 use memmap::{Mmap, MmapMut};
 use std::cell::RefCell;
 use std::convert::TryInto;
@@ -360,4 +513,5 @@ mod data_proc_simd_fp;
 mod facility;
 mod ldst;
 mod sve;
+mod sysreg;
 mod system;
@@ -100,6 +100,41 @@ pub fn disas_comp_b_imm<R: HostStorage>(
     Err(DisasException::Branch(Some(addr), Some(ctx.next_pc())))
 }
 
+pub fn disas_test_b_imm<R: HostStorage>(
+    ctx: &mut Arm64GuestContext<R>,
+    insn: InsnType,
+) -> Result<(), DisasException> {
+    let bit_pos = extract(insn, 31, 1) << 5 | extract(insn, 19, 5);
+    let op = extract(insn, 24, 1) == 1; // 0: tbz; 1: tbnz
+    let rt = extract(insn, 0, 5);
+    let addr = (ctx.curr_pc() as i64 + sextract(insn as i64, 5, 14) * 4) as usize;
+
+    let next_pc = ctx.alloc_u64(ctx.next_pc() as u64);
+    let addr_val = ctx.alloc_u64(addr as u64);
+
+    let src = ctx.reg(rt as usize);
+    let cmp = ctx.alloc_val(ValueType::U64);
+    let mask = ctx.alloc_u64(1 << bit_pos);
+    Op::push_and(ctx, &cmp, &src, &mask);
+
+    let label_match = ctx.alloc_label();
+    let zero = ctx.alloc_u64(0);
+
+    Op::push_brc(
+        ctx,
+        &label_match,
+        &cmp,
+        &zero,
+        if op { CondOp::NE } else { CondOp::EQ },
+    );
+    do_end_tb_to_addr(ctx, &next_pc, true); // branch not taken
+
+    Op::push_setlbl(ctx, &label_match);
+    do_end_tb_to_addr(ctx, &addr_val, false); // branch taken
+
+    Err(DisasException::Branch(Some(addr), Some(ctx.next_pc())))
+}
+
 pub fn disas_uncond_b_reg<R: HostStorage>(
     ctx: &mut Arm64GuestContext<R>,
     insn: InsnType,
@@ -152,4 +187,51 @@ pub fn disas_uncond_b_reg<R: HostStorage>(
     Err(DisasException::Branch(None, None))
 }
 
-disas_stub![test_b_imm, exc];
+// immediate of the `HLT` instruction that denotes a semihosting call
+const SEMIHOSTING_HLT_IMM: u32 = 0xf000;
+
+pub fn disas_exc<R: HostStorage>(
+    ctx: &mut Arm64GuestContext<R>,
+    insn: InsnType,
+) -> Result<(), DisasException> {
+    let opc = extract(insn, 21, 3);
+    let op2_ll = extract(insn, 0, 5);
+    let imm16 = extract(insn, 5, 16);
+
+    match (opc, op2_ll) {
+        (0, 1) => {
+            // svc: the syscall number and arguments are read from the guest registers by the
+            // runtime; the immediate is only reported, as Linux ignores it
+            let imm = ctx.alloc_u64(imm16 as u64);
+            let pc = ctx.alloc_u64(ctx.curr_pc() as u64);
+            Op::push_trap(ctx, TrapOp::SYSCALL, &imm, &pc);
+            Err(DisasException::Continue(ctx.next_pc()))
+        }
+        (2, 0) if imm16 == dynamic::STUB_HLT_IMM => {
+            // hlt in a native function stub: the runtime calls the host function, and the stub
+            // returns to the caller with the following ret
+            let stub = ctx.alloc_u64(ctx.curr_pc() as u64);
+            let next_pc = ctx.alloc_u64(ctx.next_pc() as u64);
+            Op::push_trap(ctx, TrapOp::DYNAMIC, &stub, &next_pc);
+            Err(DisasException::Continue(ctx.next_pc()))
+        }
+        (2, 0) if imm16 == SEMIHOSTING_HLT_IMM => {
+            // hlt for semihosting: the runtime performs the call
+            let pc = ctx.alloc_u64(ctx.curr_pc() as u64);
+            let next_pc = ctx.alloc_u64(ctx.next_pc() as u64);
+            Op::push_trap(ctx, TrapOp::SEMIHOSTING, &pc, &next_pc);
+            Err(DisasException::Continue(ctx.next_pc()))
+        }
+        (1, 0) => {
+            // brk: report the immediate and stop at the breakpoint
+            let imm = ctx.alloc_u64(imm16 as u64);
+            let pc = ctx.alloc_u64(ctx.curr_pc() as u64);
+            Op::push_trap(ctx, TrapOp::BREAKPOINT, &imm, &pc);
+            Err(DisasException::Continue(ctx.next_pc()))
+        }
+        // hvc, smc: not available in EL0
+        // hlt: halting debug is not enabled
+        // dcps1, dcps2, dcps3: debug state is not supported
+        _ => unallocated(ctx, insn),
+    }
+}
@@ -178,4 +178,122 @@ pub fn disas_logic_imm<R: HostStorage>(
     }
 }
 
-disas_stub![bitfield, extract];
+pub fn disas_bitfield<R: HostStorage>(
+    ctx: &mut Arm64GuestContext<R>,
+    insn: InsnType,
+) -> Result<(), DisasException> {
+    let sf = extract(insn, 31, 1) == 1;
+    let opc = extract(insn, 29, 2);
+    let is_n = extract(insn, 22, 1) == 1;
+    let ri = extract(insn, 16, 6) as u64;
+    let si = extract(insn, 10, 6) as u64;
+    let rn = extract(insn, 5, 5) as usize;
+    let rd = extract(insn, 0, 5) as usize;
+    let bitsize = if sf { 64 } else { 32 };
+
+    if sf != is_n || ri >= bitsize || si >= bitsize || opc > 2 {
+        return unallocated(ctx, insn);
+    }
+
+    let rd = ctx.reg(rd);
+    // ri and si are smaller than bitsize, so the upper half of a 32-bit source is never used
+    let tmp = read_cpu_reg(ctx, rn, true);
+
+    let pos;
+    let mut len;
+    if si >= ri {
+        // Wd<s-r:0> = Wn<s:r>
+        len = si - ri + 1;
+        match opc {
+            0 => {
+                // sbfm: asr, sbfx, sxtb, sxth, sxtw
+                Op::push_extrs(ctx, &rd, &tmp, ri, len);
+                if !sf {
+                    Op::push_extulq(ctx, &rd, &rd);
+                }
+                return Ok(());
+            }
+            2 => {
+                // ubfm: lsr, ubfx, uxtb, uxth
+                Op::push_extru(ctx, &rd, &tmp, ri, len);
+                return Ok(());
+            }
+            _ => {
+                // bfxil: deposit the extracted field at the bottom
+                let ri = ctx.alloc_u64(ri);
+                Op::push_shr(ctx, &tmp, &tmp, &ri);
+                pos = 0;
+            }
+        }
+    } else {
+        // Wd<32+s-r,32-r> = Wn<s:0>
+        len = si + 1;
+        pos = (bitsize - ri) & (bitsize - 1);
+    }
+
+    if opc == 0 && len < ri {
+        // sbfm: sign extend the field to fill the bits below pos
+        Op::push_extrs(ctx, &tmp, &tmp, 0, len);
+        len = ri;
+    }
+
+    if opc == 1 {
+        // bfm: bfi, bfxil
+        Op::push_depos(ctx, &rd, &rd, &tmp, pos, len);
+        if !sf {
+            Op::push_extulq(ctx, &rd, &rd);
+        }
+    } else {
+        // sbfm, ubfm: lsl, sbfiz, ubfiz
+        // nothing outside bitsize is set, no need to zero extend
+        let zero = ctx.alloc_u64(0);
+        Op::push_depos(ctx, &rd, &zero, &tmp, pos, len);
+    }
+
+    Ok(())
+}
+
+pub fn disas_extract<R: HostStorage>(
+    ctx: &mut Arm64GuestContext<R>,
+    insn: InsnType,
+) -> Result<(), DisasException> {
+    let sf = extract(insn, 31, 1) == 1;
+    let op21 = extract(insn, 29, 2);
+    let is_n = extract(insn, 22, 1) == 1;
+    let op0 = extract(insn, 21, 1);
+    let rm = extract(insn, 16, 5) as usize;
+    let imm = extract(insn, 10, 6);
+    let rn = extract(insn, 5, 5) as usize;
+    let rd = extract(insn, 0, 5) as usize;
+    let bitsize = if sf { 64 } else { 32 };
+
+    if sf != is_n || op21 != 0 || op0 != 0 || imm >= bitsize {
+        return unallocated(ctx, insn);
+    }
+
+    let rd = ctx.reg(rd);
+
+    if imm == 0 {
+        // extr with zero shift is a move of rm
+        let rm = ctx.reg(rm);
+        (if sf { Op::push_mov } else { Op::push_extulq })(ctx, &rd, &rm);
+    } else if rm == rn {
+        // ror
+        let rm = ctx.reg(rm);
+        do_shift_imm(ctx, &rd, &rm, sf, A64Shift::ROR, imm);
+    } else {
+        // Rd = Rn<imm-1:0>:Rm<bitsize-1:imm>
+        let rm = read_cpu_reg(ctx, rm, sf);
+        let rn = read_cpu_reg(ctx, rn, sf);
+        let lo_shift = ctx.alloc_u64(imm as u64);
+        let hi_shift = ctx.alloc_u64((bitsize - imm) as u64);
+        Op::push_shr(ctx, &rm, &rm, &lo_shift);
+        Op::push_shl(ctx, &rn, &rn, &hi_shift);
+        Op::push_or(ctx, &rd, &rm, &rn);
+        if !sf {
+            Op::push_extulq(ctx, &rd, &rd);
+        }
+    }
+
+    Ok(())
+}
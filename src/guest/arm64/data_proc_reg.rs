@@ -246,13 +246,508 @@ pub fn disas_cond_select<R: HostStorage>(
     Ok(())
 }
 
-disas_stub![
-    add_sub_reg,
-    adc_sbc,
-    rotate_right_into_flags,
-    evaluate_into_flags,
-    cc,
-    data_proc_1src,
-    data_proc_2src,
-    data_proc_3src
-];
+fn handle_div<R: HostStorage>(
+    ctx: &mut Arm64GuestContext<R>,
+    is_signed: bool,
+    sf: bool,
+    rm: usize,
+    rn: usize,
+    rd: usize,
+) {
+    let rd = ctx.reg(rd);
+    let n = read_cpu_reg(ctx, rn, sf);
+    let m = read_cpu_reg(ctx, rm, sf);
+    if is_signed && !sf {
+        // 32-bit operands can't overflow in 64-bit division
+        Op::push_extslq(ctx, &n, &n);
+        Op::push_extslq(ctx, &m, &m);
+    }
+
+    let zero = ctx.alloc_u64(0);
+    let one = ctx.alloc_u64(1);
+    let minus_one = ctx.alloc_u64(!0);
+    let divisor = ctx.alloc_val(ValueType::U64);
+    let result = ctx.alloc_val(ValueType::U64);
+
+    // the host may trap on division by zero and signed overflow: divide by one instead and fix
+    // up the result
+    Op::push_movc(ctx, &divisor, &one, &m, &m, &zero, CondOp::EQ);
+    if is_signed {
+        Op::push_movc(ctx, &divisor, &one, &divisor, &m, &minus_one, CondOp::EQ);
+        Op::push_div(ctx, &result, &n, &divisor);
+        // INT_MIN / -1 wraps around to INT_MIN
+        let neg = ctx.alloc_val(ValueType::U64);
+        Op::push_neg(ctx, &neg, &n);
+        Op::push_movc(ctx, &result, &neg, &result, &m, &minus_one, CondOp::EQ);
+    } else {
+        Op::push_divu(ctx, &result, &n, &divisor);
+    }
+    // division by zero yields zero
+    Op::push_movc(ctx, &rd, &zero, &result, &m, &zero, CondOp::EQ);
+
+    if !sf {
+        Op::push_extulq(ctx, &rd, &rd);
+    }
+}
+
+fn handle_shift_reg<R: HostStorage>(
+    ctx: &mut Arm64GuestContext<R>,
+    shift_type: A64Shift,
+    sf: bool,
+    rm: usize,
+    rn: usize,
+    rd: usize,
+) {
+    let rd = ctx.reg(rd);
+    let rn = read_cpu_reg(ctx, rn, sf);
+    let rm = ctx.reg(rm);
+    // the shift amount is taken modulo the register width
+    let amount = ctx.alloc_val(ValueType::U64);
+    let mask = ctx.alloc_u64(if sf { 63 } else { 31 });
+    Op::push_and(ctx, &amount, &rm, &mask);
+    do_shift(ctx, &rd, &rn, sf, shift_type, &amount);
+}
+
+fn handle_crc32<R: HostStorage>(
+    ctx: &mut Arm64GuestContext<R>,
+    sz: u32,
+    crc32c: bool,
+    rm: usize,
+    rn: usize,
+    rd: usize,
+) {
+    let rd = ctx.reg(rd);
+    let acc = ctx.reg(rn);
+    let val = ctx.reg(rm);
+    // only the lower 32 bits of the accumulator are used
+    (if crc32c {
+        Op::push_crc32c
+    } else {
+        Op::push_crc32
+    })(ctx, &rd, &acc, &val, 1 << sz);
+}
+
+pub fn disas_data_proc_2src<R: HostStorage>(
+    ctx: &mut Arm64GuestContext<R>,
+    insn: InsnType,
+) -> Result<(), DisasException> {
+    let sf = extract(insn, 31, 1) == 1;
+    let setflag = extract(insn, 29, 1) == 1;
+    let rm = extract(insn, 16, 5) as usize;
+    let opcode = extract(insn, 10, 6);
+    let rn = extract(insn, 5, 5) as usize;
+    let rd = extract(insn, 0, 5) as usize;
+
+    if setflag {
+        return unallocated(ctx, insn);
+    }
+
+    match opcode {
+        2 => handle_div(ctx, false, sf, rm, rn, rd), // udiv
+        3 => handle_div(ctx, true, sf, rm, rn, rd),  // sdiv
+        8 => handle_shift_reg(ctx, A64Shift::LSL, sf, rm, rn, rd), // lslv
+        9 => handle_shift_reg(ctx, A64Shift::LSR, sf, rm, rn, rd), // lsrv
+        10 => handle_shift_reg(ctx, A64Shift::ASR, sf, rm, rn, rd), // asrv
+        11 => handle_shift_reg(ctx, A64Shift::ROR, sf, rm, rn, rd), // rorv
+        16..=23 => {
+            // crc32, crc32c
+            let sz = extract(opcode, 0, 2);
+            let crc32c = extract(opcode, 2, 1) == 1;
+            if sf != (sz == 3) {
+                return unallocated(ctx, insn);
+            }
+            handle_crc32(ctx, sz, crc32c, rm, rn, rd);
+        }
+        _ => return unallocated(ctx, insn),
+    }
+
+    Ok(())
+}
+
+pub fn disas_data_proc_3src<R: HostStorage>(
+    ctx: &mut Arm64GuestContext<R>,
+    insn: InsnType,
+) -> Result<(), DisasException> {
+    let rd = extract(insn, 0, 5) as usize;
+    let rn = extract(insn, 5, 5) as usize;
+    let ra = extract(insn, 10, 5) as usize;
+    let rm = extract(insn, 16, 5) as usize;
+    // sf:op54:op31:o0
+    let op_id = extract(insn, 29, 3) << 4 | extract(insn, 21, 3) << 1 | extract(insn, 15, 1);
+    let sf = extract(insn, 31, 1) == 1;
+    let is_sub = extract(op_id, 0, 1) == 1;
+    let is_high = extract(op_id, 2, 1) == 1;
+
+    let is_signed = match op_id {
+        // smaddl, smsubl, smulh
+        0x42 | 0x43 | 0x44 => true,
+        // madd, msub (32 and 64 bit), umaddl, umsubl, umulh
+        0x0 | 0x1 | 0x40 | 0x41 | 0x4a | 0x4b | 0x4c => false,
+        _ => return unallocated(ctx, insn),
+    };
+
+    let rd = ctx.reg(rd);
+
+    if is_high {
+        // smulh, umulh
+        let rn = ctx.reg(rn);
+        let rm = ctx.reg(rm);
+        (if is_signed {
+            Op::push_mulsh
+        } else {
+            Op::push_muluh
+        })(ctx, &rd, &rn, &rm);
+        return Ok(());
+    }
+
+    let op1 = ctx.alloc_val(ValueType::U64);
+    let op2 = ctx.alloc_val(ValueType::U64);
+    let rn = ctx.reg(rn);
+    let rm = ctx.reg(rm);
+    let extend = if op_id < 0x42 {
+        // madd, msub
+        Op::push_mov
+    } else if is_signed {
+        // smaddl, smsubl
+        Op::push_extslq
+    } else {
+        // umaddl, umsubl
+        Op::push_extulq
+    };
+    extend(ctx, &op1, &rn);
+    extend(ctx, &op2, &rm);
+
+    if ra == 31 && !is_sub {
+        // madd with XZR is the standard encoding for mul
+        Op::push_mul(ctx, &rd, &op1, &op2);
+    } else {
+        let product = ctx.alloc_val(ValueType::U64);
+        let ra = ctx.reg(ra);
+        Op::push_mul(ctx, &product, &op1, &op2);
+        (if is_sub { Op::push_sub } else { Op::push_add })(ctx, &rd, &ra, &product);
+    }
+
+    if !sf {
+        Op::push_extulq(ctx, &rd, &rd);
+    }
+
+    Ok(())
+}
+
+fn handle_rbit<R: HostStorage>(ctx: &mut Arm64GuestContext<R>, sf: bool, rn: usize, rd: usize) {
+    let rd = ctx.reg(rd);
+    let rn = ctx.reg(rn);
+    Op::push_rbit(ctx, &rd, &rn);
+    if !sf {
+        // the reversed lower half ends up in the upper half
+        let shift = ctx.alloc_u64(32);
+        Op::push_shr(ctx, &rd, &rd, &shift);
+    }
+}
+
+fn handle_rev16<R: HostStorage>(ctx: &mut Arm64GuestContext<R>, sf: bool, rn: usize, rd: usize) {
+    let rd = ctx.reg(rd);
+    let rn = read_cpu_reg(ctx, rn, sf);
+    let tmp = ctx.alloc_val(ValueType::U64);
+    let mask = ctx.alloc_u64(if sf {
+        0x00ff_00ff_00ff_00ff
+    } else {
+        0x00ff_00ff
+    });
+    let eight = ctx.alloc_u64(8);
+
+    // swap the bytes in each halfword
+    Op::push_shr(ctx, &tmp, &rn, &eight);
+    Op::push_and(ctx, &tmp, &tmp, &mask);
+    Op::push_and(ctx, &rd, &rn, &mask);
+    Op::push_shl(ctx, &rd, &rd, &eight);
+    Op::push_or(ctx, &rd, &rd, &tmp);
+}
+
+fn handle_rev32<R: HostStorage>(ctx: &mut Arm64GuestContext<R>, sf: bool, rn: usize, rd: usize) {
+    let rd = ctx.reg(rd);
+    let rn = ctx.reg(rn);
+    let shift = ctx.alloc_u64(32);
+    Op::push_bswap(ctx, &rd, &rn);
+    if sf {
+        // rev32: swap the bytes in each word
+        Op::push_rotr(ctx, &rd, &rd, &shift);
+    } else {
+        // rev: the swapped lower half ends up in the upper half
+        Op::push_shr(ctx, &rd, &rd, &shift);
+    }
+}
+
+fn handle_clz<R: HostStorage>(ctx: &mut Arm64GuestContext<R>, sf: bool, rn: usize, rd: usize) {
+    let rd = ctx.reg(rd);
+    let rn = ctx.reg(rn);
+    if sf {
+        let width = ctx.alloc_u64(64);
+        Op::push_clz(ctx, &rd, &rn, &width);
+    } else {
+        // move the lower half to the top, such that only its leading zeroes are counted
+        let tmp = ctx.alloc_val(ValueType::U64);
+        let width = ctx.alloc_u64(32);
+        Op::push_shl(ctx, &tmp, &rn, &width);
+        Op::push_clz(ctx, &rd, &tmp, &width);
+    }
+}
+
+fn handle_cls<R: HostStorage>(ctx: &mut Arm64GuestContext<R>, sf: bool, rn: usize, rd: usize) {
+    let rd = ctx.reg(rd);
+    let rn = ctx.reg(rn);
+    if sf {
+        Op::push_cls(ctx, &rd, &rn);
+    } else {
+        // sign extension adds 32 sign bits
+        let tmp = ctx.alloc_val(ValueType::U64);
+        let width = ctx.alloc_u64(32);
+        Op::push_extslq(ctx, &tmp, &rn);
+        Op::push_cls(ctx, &tmp, &tmp);
+        Op::push_sub(ctx, &rd, &tmp, &width);
+    }
+}
+
+pub fn disas_data_proc_1src<R: HostStorage>(
+    ctx: &mut Arm64GuestContext<R>,
+    insn: InsnType,
+) -> Result<(), DisasException> {
+    let sf = extract(insn, 31, 1) == 1;
+    let opcode2 = extract(insn, 16, 5);
+    let opcode = extract(insn, 10, 6);
+    let rn = extract(insn, 5, 5) as usize;
+    let rd = extract(insn, 0, 5) as usize;
+
+    // pointer authentication is not supported
+    if extract(insn, 29, 1) == 1 || opcode2 != 0 {
+        return unallocated(ctx, insn);
+    }
+
+    match (sf, opcode) {
+        (_, 0) => handle_rbit(ctx, sf, rn, rd),
+        (_, 1) => handle_rev16(ctx, sf, rn, rd),
+        (_, 2) => handle_rev32(ctx, sf, rn, rd), // rev (32 bit), rev32
+        (true, 3) => {
+            // rev64
+            let rd = ctx.reg(rd);
+            let rn = ctx.reg(rn);
+            Op::push_bswap(ctx, &rd, &rn);
+        }
+        (_, 4) => handle_clz(ctx, sf, rn, rd),
+        (_, 5) => handle_cls(ctx, sf, rn, rd),
+        _ => return unallocated(ctx, insn),
+    }
+
+    Ok(())
+}
+
+pub fn disas_add_sub_reg<R: HostStorage>(
+    ctx: &mut Arm64GuestContext<R>,
+    insn: InsnType,
+) -> Result<(), DisasException> {
+    let rd = extract(insn, 0, 5) as usize;
+    let rn = extract(insn, 5, 5) as usize;
+    let imm6 = extract(insn, 10, 6);
+    let rm = extract(insn, 16, 5) as usize;
+    let shift_type = extract(insn, 22, 2);
+    let setflags = extract(insn, 29, 1) == 1;
+    let sub_op = extract(insn, 30, 1) == 1;
+    let sf = extract(insn, 31, 1) == 1;
+
+    if (imm6 >= 32 && !sf) || shift_type == 3 {
+        return unallocated(ctx, insn);
+    }
+
+    let rd = ctx.reg(rd);
+    let rn = read_cpu_reg(ctx, rn, sf);
+    let rm = read_cpu_reg(ctx, rm, sf);
+    let shift_type = A64Shift::from_bits(shift_type).unwrap();
+    do_shift_imm(ctx, &rm, &rm, sf, shift_type, imm6);
+
+    let result = ctx.alloc_val(ValueType::U64);
+
+    if !setflags {
+        (if sub_op { Op::push_sub } else { Op::push_add })(ctx, &result, &rn, &rm);
+    } else {
+        // update condition codes
+        if sub_op {
+            do_sub_cc(ctx, sf, &result, &rn, &rm);
+        } else {
+            do_add_cc(ctx, sf, &result, &rn, &rm);
+        }
+    }
+
+    (if sf { Op::push_mov } else { Op::push_extulq })(ctx, &rd, &result);
+
+    Ok(())
+}
+
+pub fn disas_adc_sbc<R: HostStorage>(
+    ctx: &mut Arm64GuestContext<R>,
+    insn: InsnType,
+) -> Result<(), DisasException> {
+    let sf = extract(insn, 31, 1) == 1;
+    let op = extract(insn, 30, 1) == 1;
+    let setflags = extract(insn, 29, 1) == 1;
+    let rm = extract(insn, 16, 5) as usize;
+    let rn = extract(insn, 5, 5) as usize;
+    let rd = extract(insn, 0, 5) as usize;
+
+    let rd = ctx.reg(rd);
+    let rn = ctx.reg(rn);
+    let rm = ctx.reg(rm);
+
+    // sbc: rn + !rm + carry
+    let y = if op {
+        let y = ctx.alloc_val(ValueType::U64);
+        Op::push_not(ctx, &y, &rm);
+        y
+    } else {
+        rm
+    };
+
+    (if setflags { do_adc_cc } else { do_adc })(ctx, sf, &rd, &rn, &y);
+
+    Ok(())
+}
+
+pub fn disas_cc<R: HostStorage>(
+    ctx: &mut Arm64GuestContext<R>,
+    insn: InsnType,
+) -> Result<(), DisasException> {
+    if extract(insn, 29, 1) == 0 || insn & (1 << 10 | 1 << 4) != 0 {
+        return unallocated(ctx, insn);
+    }
+
+    let sf = extract(insn, 31, 1) == 1;
+    let op = extract(insn, 30, 1) == 1;
+    let is_imm = extract(insn, 11, 1) == 1;
+    let y = extract(insn, 16, 5);
+    let cond = extract(insn, 12, 4);
+    let rn = extract(insn, 5, 5) as usize;
+    let nzcv = extract(insn, 0, 4);
+
+    // t0 = !cond
+    let Arm64CC { mut cond, value } = test_cc(ctx, cond);
+    cond.invert();
+    let t0 = ctx.alloc_val(ValueType::U32);
+    let zero = ctx.alloc_u32(0);
+    Op::push_setc(ctx, &t0, &value, &zero, cond);
+
+    let y = if is_imm {
+        ctx.alloc_u64(y as u64)
+    } else {
+        ctx.reg(y as usize)
+    };
+    let rn = ctx.reg(rn);
+
+    // set the flags for the new comparison
+    let tmp = ctx.alloc_val(ValueType::U64);
+    (if op { do_sub_cc } else { do_add_cc })(ctx, sf, &tmp, &rn, &y);
+
+    // force the flags to nzcv if cond was false, with t1 = cond ? 0 : -1
+    let (nf, zf, cf, vf) = get_flags(ctx);
+    let t1 = ctx.alloc_val(ValueType::U32);
+    Op::push_negl(ctx, &t1, &t0);
+
+    (if nzcv & 8 != 0 {
+        Op::push_orl
+    } else {
+        Op::push_andcl
+    })(ctx, &nf, &nf, &t1);
+    // zf is zero if Z is set
+    if nzcv & 4 != 0 {
+        Op::push_andcl(ctx, &zf, &zf, &t1);
+    } else {
+        Op::push_orl(ctx, &zf, &zf, &t0);
+    }
+    if nzcv & 2 != 0 {
+        Op::push_orl(ctx, &cf, &cf, &t0);
+    } else {
+        Op::push_andcl(ctx, &cf, &cf, &t1);
+    }
+    (if nzcv & 1 != 0 {
+        Op::push_orl
+    } else {
+        Op::push_andcl
+    })(ctx, &vf, &vf, &t1);
+
+    Ok(())
+}
+
+pub fn disas_rotate_right_into_flags<R: HostStorage>(
+    ctx: &mut Arm64GuestContext<R>,
+    insn: InsnType,
+) -> Result<(), DisasException> {
+    let mask = extract(insn, 0, 4);
+    let o2 = extract(insn, 4, 1);
+    let rn = extract(insn, 5, 5) as usize;
+    let imm6 = extract(insn, 15, 6);
+    let sf_op_s = extract(insn, 29, 3);
+
+    if sf_op_s != 5 || o2 != 0 {
+        return unallocated(ctx, insn);
+    }
+
+    // rmif: nzcv = (rn ror imm6)<3:0>
+    let nzcv = read_cpu_reg(ctx, rn, true);
+    do_shift_imm(ctx, &nzcv, &nzcv, true, A64Shift::ROR, imm6);
+
+    let (nf, zf, cf, vf) = get_flags(ctx);
+    let tmp = ctx.alloc_val(ValueType::U64);
+    if mask & 8 != 0 {
+        let shift = ctx.alloc_u64(31 - 3);
+        Op::push_shl(ctx, &tmp, &nzcv, &shift);
+        Op::push_extrl(ctx, &nf, &tmp);
+    }
+    if mask & 4 != 0 {
+        // zf is zero if Z is set
+        let bit = ctx.alloc_u64(4);
+        Op::push_andc(ctx, &tmp, &bit, &nzcv);
+        Op::push_extrl(ctx, &zf, &tmp);
+    }
+    if mask & 2 != 0 {
+        Op::push_extru(ctx, &tmp, &nzcv, 1, 1);
+        Op::push_extrl(ctx, &cf, &tmp);
+    }
+    if mask & 1 != 0 {
+        let shift = ctx.alloc_u64(31);
+        Op::push_shl(ctx, &tmp, &nzcv, &shift);
+        Op::push_extrl(ctx, &vf, &tmp);
+    }
+
+    Ok(())
+}
+
+pub fn disas_evaluate_into_flags<R: HostStorage>(
+    ctx: &mut Arm64GuestContext<R>,
+    insn: InsnType,
+) -> Result<(), DisasException> {
+    let o3_mask = extract(insn, 0, 5);
+    let rn = extract(insn, 5, 5) as usize;
+    let o2 = extract(insn, 15, 6);
+    let sz = extract(insn, 14, 1);
+    let sf_op_s = extract(insn, 29, 3);
+
+    if sf_op_s != 1 || o2 != 0 || o3_mask != 0xd {
+        return unallocated(ctx, insn);
+    }
+
+    // setf16 or setf8: move the sign bit of the halfword or byte to bit 31
+    let shift = if sz == 1 { 16 } else { 24 };
+    let (nf, zf, _, vf) = get_flags(ctx);
+    let rn = ctx.reg(rn);
+    let tmp = ctx.alloc_val(ValueType::U64);
+    let n_shift = ctx.alloc_u64(shift);
+    let v_shift = ctx.alloc_u64(shift - 1);
+
+    Op::push_shl(ctx, &tmp, &rn, &n_shift);
+    Op::push_extrl(ctx, &nf, &tmp);
+    Op::push_mov(ctx, &zf, &nf);
+    // V is set if the bit above the sign bit differs from it
+    Op::push_shl(ctx, &tmp, &rn, &v_shift);
+    Op::push_extrl(ctx, &vf, &tmp);
+    Op::push_xorl(ctx, &vf, &vf, &nf);
+
+    Ok(())
+}
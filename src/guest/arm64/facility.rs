@@ -67,6 +67,33 @@ pub fn do_ldst<R: HostStorage>(
     size: u64,
     reg: &Rc<KHVal<R>>,
     addr: &Rc<KHVal<R>>,
+) {
+    do_ldst_order(ctx, is_load, sign, extend, size, reg, addr, MemOp::empty());
+}
+
+// memory ordering for load-acquire / store-release and the acquire / release variants of the
+// atomic instructions.  These are RCsc in ARM64: a store-release followed by a load-acquire is
+// observed in program order.  Host acquire and release accesses do not guarantee this (e.g. the
+// store buffer in x86 TSO lets the load pass the store), so they are made sequentially
+// consistent.  The RCpc LDAPR family only needs acquire semantics and does not use this.
+pub fn rcsc_order(acquire: bool, release: bool) -> MemOp {
+    if acquire || release {
+        MemOp::SEQ_CST
+    } else {
+        MemOp::empty()
+    }
+}
+
+// generate load / store with the memory ordering in `order`
+pub fn do_ldst_order<R: HostStorage>(
+    ctx: &mut Arm64GuestContext<R>,
+    is_load: bool,
+    sign: bool,
+    extend: bool,
+    size: u64,
+    reg: &Rc<KHVal<R>>,
+    addr: &Rc<KHVal<R>>,
+    order: MemOp,
 ) {
     (if is_load {
         Op::push_load
@@ -76,7 +103,7 @@ pub fn do_ldst<R: HostStorage>(
         ctx,
         reg,
         addr,
-        MemOp::from_sign(sign) | MemOp::from_size(size) | MemOp::GUEST_LE,
+        MemOp::from_sign(sign) | MemOp::from_size(size) | MemOp::GUEST_LE | order,
     );
 
     if is_load && extend && sign {
@@ -85,7 +112,7 @@ pub fn do_ldst<R: HostStorage>(
     }
 }
 
-fn get_flags<R: HostStorage>(
+pub fn get_flags<R: HostStorage>(
     ctx: &mut Arm64GuestContext<R>,
 ) -> (Rc<KHVal<R>>, Rc<KHVal<R>>, Rc<KHVal<R>>, Rc<KHVal<R>>) {
     (
@@ -183,6 +210,71 @@ pub fn do_sub_cc<R: HostStorage>(
     }
 }
 
+// generate add with carry in, without condition code modification
+pub fn do_adc<R: HostStorage>(
+    ctx: &mut Arm64GuestContext<R>,
+    sf: bool,
+    dest: &Rc<KHVal<R>>,
+    t0: &Rc<KHVal<R>>,
+    t1: &Rc<KHVal<R>>,
+) {
+    let (_, _, cf, _) = get_flags(ctx);
+    let carry = ctx.alloc_val(ValueType::U64);
+    let tmp = ctx.alloc_val(ValueType::U64);
+
+    Op::push_extulq(ctx, &carry, &cf);
+    Op::push_add(ctx, &tmp, t0, t1);
+    Op::push_add(ctx, dest, &tmp, &carry);
+    if !sf {
+        Op::push_extulq(ctx, dest, dest);
+    }
+}
+
+// generate add with carry in and condition code modification
+pub fn do_adc_cc<R: HostStorage>(
+    ctx: &mut Arm64GuestContext<R>,
+    sf: bool,
+    dest: &Rc<KHVal<R>>,
+    t0: &Rc<KHVal<R>>,
+    t1: &Rc<KHVal<R>>,
+) {
+    let (nf, zf, cf, vf) = get_flags(ctx);
+    if sf {
+        let result = ctx.alloc_val(ValueType::U64);
+        let flag = ctx.alloc_val(ValueType::U64);
+        let tmp = ctx.alloc_val(ValueType::U64);
+
+        let zero = ctx.alloc_u64(0);
+        // capture carry of both additions in flag
+        Op::push_extulq(ctx, &tmp, &cf);
+        Op::push_add2(ctx, &result, &flag, t0, &zero, &tmp, &zero);
+        Op::push_add2(ctx, &result, &flag, &result, &flag, t1, &zero);
+        Op::push_extrl(ctx, &cf, &flag);
+        set_nz64(ctx, &result);
+        // calculate vf
+        Op::push_xor(ctx, &flag, &result, t0);
+        Op::push_xor(ctx, &tmp, t0, t1);
+        Op::push_andc(ctx, &flag, &flag, &tmp);
+        Op::push_extrh(ctx, &vf, &flag);
+        Op::push_mov(ctx, dest, &result);
+    } else {
+        let t0_32 = ctx.alloc_val(ValueType::U32);
+        let t1_32 = ctx.alloc_val(ValueType::U32);
+        let tmp = ctx.alloc_val(ValueType::U32);
+
+        let zero = ctx.alloc_u32(0);
+        Op::push_extrl(ctx, &t0_32, t0);
+        Op::push_extrl(ctx, &t1_32, t1);
+        Op::push_add2l(ctx, &nf, &cf, &t0_32, &zero, &cf, &zero);
+        Op::push_add2l(ctx, &nf, &cf, &nf, &cf, &t1_32, &zero);
+        Op::push_mov(ctx, &zf, &nf);
+        Op::push_xorl(ctx, &vf, &nf, &t0_32);
+        Op::push_xorl(ctx, &tmp, &t0_32, &t1_32);
+        Op::push_andcl(ctx, &vf, &vf, &tmp);
+        Op::push_extulq(ctx, dest, &nf);
+    }
+}
+
 pub struct Arm64CC<R: HostStorage> {
     pub cond: CondOp,
     pub value: Rc<KHVal<R>>,
@@ -262,7 +354,8 @@ pub fn do_test_jump_cc<R: HostStorage>(
     Op::push_brc(ctx, label, &value, &zero, cond);
 }
 
-// set PC and return to runtime to find out next TB
+// set PC and return to runtime to find out next TB; the backend may chain the exit to the next
+// TB directly once it is known
 pub fn do_end_tb_to_addr<R: HostStorage>(
     ctx: &mut Arm64GuestContext<R>,
     dest: &Rc<KHVal<R>>,
@@ -270,7 +363,7 @@ pub fn do_end_tb_to_addr<R: HostStorage>(
 ) {
     let pc = Rc::clone(&ctx.pc);
     Op::push_mov(ctx, &pc, dest);
-    Op::push_trap(ctx, TrapOp::LOOKUP_TB, dest);
+    Op::push_trap(ctx, TrapOp::LOOKUP_TB, dest, dest);
     if is_aux {
         ctx.set_aux_chain();
     } else {
@@ -362,7 +455,7 @@ pub fn do_ext_and_shift_reg<R: HostStorage>(
     (if is_signed {
         match extsize {
             0 => Op::push_extsbq,
-            1 => Op::push_extslq,
+            1 => Op::push_extswq,
             2 => Op::push_extslq,
             3 => Op::push_mov,
             _ => unreachable!(),
@@ -370,7 +463,7 @@ pub fn do_ext_and_shift_reg<R: HostStorage>(
     } else {
         match extsize {
             0 => Op::push_extubq,
-            1 => Op::push_extulq,
+            1 => Op::push_extuwq,
             2 => Op::push_extulq,
             3 => Op::push_mov,
             _ => unreachable!(),
@@ -472,7 +565,8 @@ pub fn logic_imm_decode_wmask(immn: u32, imms: u32, immr: u32) -> Option<u64> {
 // if not enabled, the caller should not emit any code for the instruction
 pub fn fp_access_check<R: HostStorage>(ctx: &mut Arm64GuestContext<R>) -> bool {
     // FP not enabled yet, always disabled
-    Op::push_trap(ctx, TrapOp::UNDEF_OPCODE, &Rc::clone(&ctx.pc));
+    let pc = ctx.alloc_u64(ctx.curr_pc() as u64);
+    Op::push_trap(ctx, TrapOp::UNDEF_OPCODE, &pc, &pc);
 
     false
 }
@@ -126,6 +126,303 @@ fn check_sp_alignment<R: HostStorage>(ctx: &mut Arm64GuestContext<R>) {
     /* sp alignment check as specified in AArch64 omitted */
 }
 
+// load exclusive: tag the address in the exclusive monitor and remember the value loaded
+fn do_load_exclusive<R: HostStorage>(
+    ctx: &mut Arm64GuestContext<R>,
+    rt: usize,
+    rt2: usize,
+    addr: &Rc<KHVal<R>>,
+    size: u32,
+    is_pair: bool,
+    is_acquire: bool,
+) {
+    let excl_addr = Rc::clone(&ctx.excl_addr);
+    let excl_size = Rc::clone(&ctx.excl_size);
+    let excl_val = Rc::clone(&ctx.excl_val);
+    let excl_high = Rc::clone(&ctx.excl_high);
+    let order = rcsc_order(is_acquire, false);
+    let rt = ctx.reg(rt);
+    let bytes = 1 << size as u64;
+
+    if is_pair {
+        let rt2 = ctx.reg(rt2);
+        if size == 2 {
+            // a pair of 32-bit registers is loaded as a single 64-bit value
+            do_ldst_order(ctx, true, false, false, 8, &excl_val, addr, order);
+            Op::push_extru(ctx, &rt, &excl_val, 0, 32);
+            Op::push_extru(ctx, &rt2, &excl_val, 32, 32);
+        } else {
+            let addr_high = ctx.alloc_val(ValueType::U64);
+            let eight = ctx.alloc_u64(8);
+            Op::push_add(ctx, &addr_high, addr, &eight);
+            do_ldst_order(ctx, true, false, false, 8, &excl_val, addr, order);
+            do_ldst_order(ctx, true, false, false, 8, &excl_high, &addr_high, order);
+            Op::push_mov(ctx, &rt, &excl_val);
+            Op::push_mov(ctx, &rt2, &excl_high);
+        }
+    } else {
+        do_ldst_order(ctx, true, false, false, bytes, &excl_val, addr, order);
+        Op::push_mov(ctx, &rt, &excl_val);
+    }
+
+    let total = ctx.alloc_u64(if is_pair { bytes * 2 } else { bytes });
+    Op::push_mov(ctx, &excl_addr, addr);
+    Op::push_mov(ctx, &excl_size, &total);
+}
+
+// store exclusive: the store only happens if the monitor still tags the address with the same
+// size, and memory still holds the value seen by the load exclusive.  The latter is checked with
+// a host compare and exchange, so that stores from other guest threads make the store fail.
+//
+// Note that stores of the same value from other threads in between go unnoticed (the ABA
+// problem); this is harmless for the usual load exclusive / store exclusive sequences.
+fn do_store_exclusive<R: HostStorage>(
+    ctx: &mut Arm64GuestContext<R>,
+    rs: usize,
+    rt: usize,
+    rt2: usize,
+    addr: &Rc<KHVal<R>>,
+    size: u32,
+    is_pair: bool,
+    is_release: bool,
+) {
+    let excl_addr = Rc::clone(&ctx.excl_addr);
+    let excl_size = Rc::clone(&ctx.excl_size);
+    let excl_val = Rc::clone(&ctx.excl_val);
+    let excl_high = Rc::clone(&ctx.excl_high);
+    let order = rcsc_order(false, is_release);
+    let rs = ctx.reg(rs);
+    let rt = ctx.reg(rt);
+    let bytes = 1 << size as u64;
+    let total = if is_pair { bytes * 2 } else { bytes };
+
+    let fail_label = ctx.alloc_label();
+    let done_label = ctx.alloc_label();
+    let total_val = ctx.alloc_u64(total);
+    Op::push_brc(ctx, &fail_label, addr, &excl_addr, CondOp::NE);
+    Op::push_brc(ctx, &fail_label, &excl_size, &total_val, CondOp::NE);
+
+    let tmp = ctx.alloc_val(ValueType::U64);
+    if is_pair && size == 3 {
+        // a pair of 64-bit registers is stored with a 128-bit compare and exchange
+        let rt2 = ctx.reg(rt2);
+        let tmp_high = ctx.alloc_val(ValueType::U64);
+        let fail_high = ctx.alloc_val(ValueType::U64);
+        Op::push_cmpxchg2(
+            ctx,
+            &tmp,
+            &tmp_high,
+            addr,
+            &excl_val,
+            &excl_high,
+            &rt,
+            &rt2,
+            MemOp::Q | MemOp::GUEST_LE | order,
+        );
+        Op::push_setc(ctx, &rs, &tmp, &excl_val, CondOp::NE);
+        Op::push_setc(ctx, &fail_high, &tmp_high, &excl_high, CondOp::NE);
+        Op::push_or(ctx, &rs, &rs, &fail_high);
+    } else {
+        let val = if is_pair {
+            // a pair of 32-bit registers is stored as a single 64-bit value
+            let rt2 = ctx.reg(rt2);
+            let val = ctx.alloc_val(ValueType::U64);
+            Op::push_depos(ctx, &val, &rt, &rt2, 32, 32);
+            val
+        } else {
+            rt
+        };
+        Op::push_cmpxchg(
+            ctx,
+            &tmp,
+            addr,
+            &excl_val,
+            &val,
+            MemOp::from_size(total) | MemOp::GUEST_LE | order,
+        );
+        Op::push_setc(ctx, &rs, &tmp, &excl_val, CondOp::NE);
+    }
+    let zero = ctx.alloc_u64(0);
+    Op::push_brc(ctx, &done_label, &zero, &zero, CondOp::ALWAYS);
+
+    Op::push_setlbl(ctx, &fail_label);
+    let one = ctx.alloc_u64(1);
+    Op::push_mov(ctx, &rs, &one);
+
+    Op::push_setlbl(ctx, &done_label);
+    // the monitor goes back to the Open Access state regardless of the outcome
+    let open = ctx.alloc_u64(!0);
+    Op::push_mov(ctx, &excl_addr, &open);
+}
+
+fn do_compare_and_swap<R: HostStorage>(
+    ctx: &mut Arm64GuestContext<R>,
+    rs: usize,
+    rt: usize,
+    rn: usize,
+    size: u32,
+    order: MemOp,
+) {
+    if rn == 31 {
+        check_sp_alignment(ctx);
+    }
+    let dirty_addr = read_cpu_reg_sp(ctx, rn, true);
+    let clean_addr = clean_data_tbi(ctx, &dirty_addr);
+    let rs = ctx.reg(rs);
+    let rt = ctx.reg(rt);
+
+    Op::push_cmpxchg(
+        ctx,
+        &rs,
+        &clean_addr,
+        &rs,
+        &rt,
+        MemOp::from_size(1 << size as u64) | MemOp::GUEST_LE | order,
+    );
+}
+
+fn do_compare_and_swap_pair<R: HostStorage>(
+    ctx: &mut Arm64GuestContext<R>,
+    rs: usize,
+    rt: usize,
+    rn: usize,
+    size: u32,
+    order: MemOp,
+) {
+    if rn == 31 {
+        check_sp_alignment(ctx);
+    }
+    let dirty_addr = read_cpu_reg_sp(ctx, rn, true);
+    let clean_addr = clean_data_tbi(ctx, &dirty_addr);
+    let s1 = ctx.reg(rs);
+    let s2 = ctx.reg(rs + 1);
+    let t1 = ctx.reg(rt);
+    let t2 = ctx.reg(rt + 1);
+
+    if size == 2 {
+        // a pair of 32-bit registers is swapped as a single 64-bit value
+        let cmp = ctx.alloc_val(ValueType::U64);
+        let new = ctx.alloc_val(ValueType::U64);
+        let old = ctx.alloc_val(ValueType::U64);
+        Op::push_depos(ctx, &cmp, &s1, &s2, 32, 32);
+        Op::push_depos(ctx, &new, &t1, &t2, 32, 32);
+        Op::push_cmpxchg(
+            ctx,
+            &old,
+            &clean_addr,
+            &cmp,
+            &new,
+            MemOp::from_size(8) | MemOp::GUEST_LE | order,
+        );
+        Op::push_extru(ctx, &s1, &old, 0, 32);
+        Op::push_extru(ctx, &s2, &old, 32, 32);
+    } else {
+        Op::push_cmpxchg2(
+            ctx,
+            &s1,
+            &s2,
+            &clean_addr,
+            &s1,
+            &s2,
+            &t1,
+            &t2,
+            MemOp::Q | MemOp::GUEST_LE | order,
+        );
+    }
+}
+
+pub fn disas_ldst_excl<R: HostStorage>(
+    ctx: &mut Arm64GuestContext<R>,
+    insn: InsnType,
+) -> Result<(), DisasException> {
+    trace!("ldst_excl");
+    let rt = extract(insn, 0, 5) as usize;
+    let rn = extract(insn, 5, 5) as usize;
+    let rt2 = extract(insn, 10, 5) as usize;
+    let rs = extract(insn, 16, 5) as usize;
+    let is_lasr = extract(insn, 15, 1);
+    let o2_l_o1_o0 = extract(insn, 21, 3) * 2 | is_lasr;
+    let size = extract(insn, 30, 2);
+
+    match o2_l_o1_o0 {
+        // STXR, STLXR
+        0x0 | 0x1 => {
+            if rn == 31 {
+                check_sp_alignment(ctx);
+            }
+            let dirty_addr = read_cpu_reg_sp(ctx, rn, true);
+            let clean_addr = clean_data_tbi(ctx, &dirty_addr);
+            do_store_exclusive(ctx, rs, rt, rt2, &clean_addr, size, false, is_lasr == 1);
+            Ok(())
+        }
+        // LDXR, LDAXR
+        0x4 | 0x5 => {
+            if rn == 31 {
+                check_sp_alignment(ctx);
+            }
+            let dirty_addr = read_cpu_reg_sp(ctx, rn, true);
+            let clean_addr = clean_data_tbi(ctx, &dirty_addr);
+            do_load_exclusive(ctx, rt, rt2, &clean_addr, size, false, is_lasr == 1);
+            Ok(())
+        }
+        // STXP, STLXP
+        0x2 | 0x3 if size & 2 != 0 => {
+            if rn == 31 {
+                check_sp_alignment(ctx);
+            }
+            let dirty_addr = read_cpu_reg_sp(ctx, rn, true);
+            let clean_addr = clean_data_tbi(ctx, &dirty_addr);
+            do_store_exclusive(ctx, rs, rt, rt2, &clean_addr, size, true, is_lasr == 1);
+            Ok(())
+        }
+        // LDXP, LDAXP
+        0x6 | 0x7 if size & 2 != 0 => {
+            if rn == 31 {
+                check_sp_alignment(ctx);
+            }
+            let dirty_addr = read_cpu_reg_sp(ctx, rn, true);
+            let clean_addr = clean_data_tbi(ctx, &dirty_addr);
+            do_load_exclusive(ctx, rt, rt2, &clean_addr, size, true, is_lasr == 1);
+            Ok(())
+        }
+        // STLLR, STLR, LDLAR, LDAR; without LORegions the LOAcquire / LORelease variants are
+        // the same as load-acquire / store-release
+        0x8 | 0x9 | 0xc | 0xd => {
+            let is_load = o2_l_o1_o0 & 0x4 != 0;
+            if rn == 31 {
+                check_sp_alignment(ctx);
+            }
+            let dirty_addr = read_cpu_reg_sp(ctx, rn, true);
+            let clean_addr = clean_data_tbi(ctx, &dirty_addr);
+            let rt = ctx.reg(rt);
+            do_ldst_order(
+                ctx,
+                is_load,
+                false,
+                false,
+                1 << size as u64,
+                &rt,
+                &clean_addr,
+                rcsc_order(is_load, !is_load),
+            );
+            Ok(())
+        }
+        // CASP, CASPL, CASPA, CASPAL
+        0x2 | 0x3 | 0x6 | 0x7 if rt2 == 31 && (rt | rs) & 1 == 0 => {
+            let order = rcsc_order(extract(insn, 22, 1) == 1, is_lasr == 1);
+            do_compare_and_swap_pair(ctx, rs, rt, rn, size | 2, order);
+            Ok(())
+        }
+        // CAS, CASL, CASA, CASAL
+        0xa | 0xb | 0xe | 0xf if rt2 == 31 => {
+            let order = rcsc_order(extract(insn, 22, 1) == 1, is_lasr == 1);
+            do_compare_and_swap(ctx, rs, rt, rn, size, order);
+            Ok(())
+        }
+        _ => unallocated(ctx, insn),
+    }
+}
+
 pub fn disas_ldst_reg_imm9<R: HostStorage>(
     ctx: &mut Arm64GuestContext<R>,
     insn: InsnType,
@@ -243,9 +540,77 @@ pub fn disas_ldst_atomic<R: HostStorage>(
     rt: usize,
     is_vector: bool,
 ) -> Result<(), DisasException> {
-    Err(DisasException::Unexpected(
-        "ldst_atomic work in progress".to_owned(),
-    ))
+    trace!("ldst_atomic");
+    let rs = extract(insn, 16, 5) as usize;
+    let rn = extract(insn, 5, 5) as usize;
+    let o3_opc = extract(insn, 12, 4);
+    let is_release = extract(insn, 22, 1) == 1;
+    let is_acquire = extract(insn, 23, 1) == 1;
+
+    if is_vector {
+        return unallocated(ctx, insn);
+    }
+
+    let atomic_op = match o3_opc {
+        0o00 => AtomicOp::ADD,
+        0o01 => AtomicOp::AND, // LDCLR: and with the complement
+        0o02 => AtomicOp::XOR,
+        0o03 => AtomicOp::OR,
+        0o04 => AtomicOp::SMAX,
+        0o05 => AtomicOp::SMIN,
+        0o06 => AtomicOp::UMAX,
+        0o07 => AtomicOp::UMIN,
+        0o10 => AtomicOp::XCHG,
+        0o14 => {
+            // LDAPRB, LDAPRH, LDAPR
+            if rs != 31 || !is_acquire || is_release {
+                return unallocated(ctx, insn);
+            }
+            if rn == 31 {
+                check_sp_alignment(ctx);
+            }
+            let dirty_addr = read_cpu_reg_sp(ctx, rn, true);
+            let clean_addr = clean_data_tbi(ctx, &dirty_addr);
+            let rt = ctx.reg(rt);
+            // RCpc: a preceding store-release may be observed later, so acquire suffices
+            do_ldst_order(
+                ctx,
+                true,
+                false,
+                false,
+                1 << size as u64,
+                &rt,
+                &clean_addr,
+                MemOp::ACQUIRE,
+            );
+            return Ok(());
+        }
+        _ => return unallocated(ctx, insn),
+    };
+
+    if rn == 31 {
+        check_sp_alignment(ctx);
+    }
+    let dirty_addr = read_cpu_reg_sp(ctx, rn, true);
+    let clean_addr = clean_data_tbi(ctx, &dirty_addr);
+
+    let val = read_cpu_reg(ctx, rs, true);
+    if o3_opc == 0o01 {
+        Op::push_not(ctx, &val, &val);
+    }
+
+    // the old value is zero-extended, also for the signed minimum and maximum
+    let rt = ctx.reg(rt);
+    Op::push_atomicrmw(
+        ctx,
+        &rt,
+        &clean_addr,
+        &val,
+        atomic_op,
+        MemOp::from_size(1 << size as u64) | MemOp::GUEST_LE | rcsc_order(is_acquire, is_release),
+    );
+
+    Ok(())
 }
 
 pub fn disas_ldst_reg_offset<R: HostStorage>(
@@ -256,9 +621,73 @@ pub fn disas_ldst_reg_offset<R: HostStorage>(
     rt: usize,
     is_vector: bool,
 ) -> Result<(), DisasException> {
-    Err(DisasException::Unexpected(
-        "ldst_reg_offset work in progress".to_owned(),
-    ))
+    trace!("ldst_reg_offset");
+    let rn = extract(insn, 5, 5) as usize;
+    let shift = extract(insn, 12, 1) == 1;
+    let option = extract(insn, 13, 3);
+    let rm = extract(insn, 16, 5) as usize;
+
+    let is_store;
+    let is_signed;
+    let mut is_extended = false;
+
+    let mut size = size;
+
+    if extract(option, 1, 1) == 0 {
+        return unallocated(ctx, insn);
+    }
+
+    if is_vector {
+        is_signed = false;
+        size |= (opc & 2) << 1;
+        if size > 4 {
+            return unallocated(ctx, insn);
+        }
+        is_store = extract(opc, 0, 1) == 0;
+        if !fp_access_check(ctx) {
+            return Ok(());
+        }
+    } else {
+        if size == 3 && opc == 2 {
+            // prfm - prefetch, ignore
+            return Ok(());
+        }
+        if opc == 3 && size > 1 {
+            return unallocated(ctx, insn);
+        }
+        is_store = opc == 0;
+        is_signed = extract(opc, 1, 1) == 1;
+        is_extended = size < 3 && extract(opc, 0, 1) == 1;
+    }
+
+    if rn == 31 {
+        check_sp_alignment(ctx);
+    }
+    let dirty_addr = read_cpu_reg_sp(ctx, rn, true);
+    let offset = read_cpu_reg(ctx, rm, true);
+    do_ext_and_shift_reg(ctx, &offset, &offset, option, if shift { size } else { 0 });
+    Op::push_add(ctx, &dirty_addr, &dirty_addr, &offset);
+    let clean_addr = clean_data_tbi(ctx, &dirty_addr);
+
+    let size = 1 << size as u64;
+
+    if is_vector {
+        // TODO(jsteward) implement vector/SIMD
+        return unallocated(ctx, insn);
+    } else {
+        let rt = ctx.reg(rt);
+        do_ldst(
+            ctx,
+            !is_store,
+            is_signed,
+            is_extended,
+            size,
+            &rt,
+            &clean_addr,
+        );
+    }
+
+    Ok(())
 }
 
 pub fn disas_ldst_pac<R: HostStorage>(
@@ -375,10 +804,155 @@ pub fn disas_ldst_reg<R: HostStorage>(
     }
 }
 
-disas_stub![
-    ldst_excl,
-    ld_lit,
-    ldst_multiple_struct,
-    ldst_single_struct,
-    ldst_ldapr_stlr
-];
+pub fn disas_ld_lit<R: HostStorage>(
+    ctx: &mut Arm64GuestContext<R>,
+    insn: InsnType,
+) -> Result<(), DisasException> {
+    trace!("ld_lit");
+    let rt = extract(insn, 0, 5) as usize;
+    let imm = sextract(insn as i64, 5, 19) << 2;
+    let is_vector = extract(insn, 26, 1) == 1;
+    let opc = extract(insn, 30, 2);
+
+    let size;
+    let mut is_signed = false;
+
+    if is_vector {
+        if opc == 3 {
+            return unallocated(ctx, insn);
+        }
+        size = 2 + opc;
+        if !fp_access_check(ctx) {
+            return Ok(());
+        }
+    } else {
+        if opc == 3 {
+            // prfm - prefetch, ignore
+            return Ok(());
+        }
+        size = 2 + extract(opc, 0, 1);
+        is_signed = extract(opc, 1, 1) == 1;
+    }
+
+    // the address is known at translation time
+    let addr = (ctx.curr_pc() as i64 + imm) as u64;
+    let size = 1 << size as u64;
+
+    if is_vector {
+        // TODO(jsteward) implement vector/SIMD
+        return unallocated(ctx, insn);
+    }
+
+    let rt = ctx.reg(rt);
+    let literal = if ctx.fold_literal {
+        read_only_literal(&ctx.map.borrow(), addr, size)
+    } else {
+        None
+    };
+    match literal {
+        Some(v) => {
+            let v = if is_signed {
+                sextract(v as i64, 0, size as usize * 8) as u64
+            } else {
+                v
+            };
+            trace!("Folding literal {:#x} at {:#x}", v, addr);
+            let v = ctx.alloc_u64(v);
+            Op::push_mov(ctx, &rt, &v);
+        }
+        None => {
+            let addr = ctx.alloc_u64(addr);
+            do_ldst(ctx, true, is_signed, false, size, &rt, &addr);
+        }
+    }
+
+    Ok(())
+}
+
+// value of the literal at `addr` if it can't change, i.e. the page is not writable
+fn read_only_literal(vm: &GuestVM, addr: u64, size: u64) -> Option<u64> {
+    if !vm.check(addr, size, Perm::READ) || (addr..addr + size).any(|a| vm.check(a, 1, Perm::WRITE))
+    {
+        return None;
+    }
+    let mut bytes = [0; 8];
+    bytes[..size as usize].copy_from_slice(&vm[addr as usize..(addr + size) as usize]);
+    Some(u64::from_le_bytes(bytes))
+}
+
+pub fn disas_ldst_ldapr_stlr<R: HostStorage>(
+    ctx: &mut Arm64GuestContext<R>,
+    insn: InsnType,
+) -> Result<(), DisasException> {
+    trace!("ldst_ldapr_stlr");
+    let rt = extract(insn, 0, 5) as usize;
+    let rn = extract(insn, 5, 5) as usize;
+    let offset = sextract(insn as i64, 12, 9);
+    let opc = extract(insn, 22, 2);
+    let size = extract(insn, 30, 2);
+
+    if extract(insn, 21, 1) != 0 || extract(insn, 10, 2) != 0 {
+        // memory tagging not supported
+        return unallocated(ctx, insn);
+    }
+
+    let mut is_store = false;
+    let mut is_signed = false;
+    let mut is_extended = false;
+    match opc {
+        // STLUR
+        0 => is_store = true,
+        // LDAPUR
+        1 => {}
+        // LDAPURS, 64-bit variant
+        2 => {
+            if size == 3 {
+                return unallocated(ctx, insn);
+            }
+            is_signed = true;
+        }
+        // LDAPURS, 32-bit variant
+        3 => {
+            if size > 1 {
+                return unallocated(ctx, insn);
+            }
+            is_signed = true;
+            is_extended = true;
+        }
+        _ => unreachable!(),
+    }
+
+    if rn == 31 {
+        check_sp_alignment(ctx);
+    }
+
+    let dirty_addr = read_cpu_reg_sp(ctx, rn, true);
+    let offset_val = ctx.alloc_u64(offset.abs().try_into().unwrap());
+    (if offset >= 0 {
+        Op::push_add
+    } else {
+        Op::push_sub
+    })(ctx, &dirty_addr, &dirty_addr, &offset_val);
+    let clean_addr = clean_data_tbi(ctx, &dirty_addr);
+
+    // STLUR is a store-release as STLR, LDAPUR is RCpc as LDAPR
+    let rt = ctx.reg(rt);
+    do_ldst_order(
+        ctx,
+        !is_store,
+        is_signed,
+        is_extended,
+        1 << size as u64,
+        &rt,
+        &clean_addr,
+        if is_store {
+            rcsc_order(false, true)
+        } else {
+            MemOp::ACQUIRE
+        },
+    );
+
+    Ok(())
+}
+
+disas_stub![ldst_multiple_struct, ldst_single_struct];
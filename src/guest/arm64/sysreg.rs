@@ -0,0 +1,252 @@
+// SPDX-FileCopyrightText: 2020 Pengcheng Xu <i@jsteward.moe>
+//
+// SPDX-License-Identifier: BSD-3-Clause
+
+use super::*;
+
+bitflags! {
+    /// Accessibility of a system register from EL0.
+    ///
+    /// For system instructions, `WRITE` allows `SYS` and `READ` allows `SYSL`.
+    pub struct SysRegAccess: u32 {
+        const READ  = 0b01;
+        const WRITE = 0b10;
+        const RW    = Self::READ.bits | Self::WRITE.bits;
+    }
+}
+
+/// Storage backing a system register.
+pub enum SysRegStorage {
+    /// Constant value.
+    Const(u64),
+    /// Field in [`Arm64CpuState`](../struct.Arm64CpuState.html), given as a function returning
+    /// the byte offset of the field.
+    State(fn() -> usize),
+    /// The NZCV flags, assembled from and split into the `nf`, `zf`, `cf` and `vf` registers.
+    Nzcv,
+    /// Host function called from the translated code when the register is read.
+    Helper(extern "C" fn() -> u64),
+    /// System instruction without effect on the emulation.
+    Nop,
+    /// `DC ZVA`: zero a block of memory.
+    DcZva,
+    /// `IC IVAU`: invalidate the translated code of an instruction cache line.
+    IcIvau,
+}
+
+/// A system register accessed with `MRS` and `MSR`, or a system instruction accessed with `SYS`.
+pub struct SysReg {
+    pub name: &'static str,
+    /// Encoding of the register: `(op0, op1, CRn, CRm, op2)`.
+    pub key: (u32, u32, u32, u32, u32),
+    pub storage: SysRegStorage,
+    pub access: SysRegAccess,
+}
+
+/// Frequency of the virtual counter in `CNTVCT_EL0`, which counts nanoseconds of the host
+/// monotonic clock.
+pub const CNTFRQ: u64 = 1_000_000_000;
+
+// the ID registers are only accessible from EL1, but Linux emulates reads from EL0
+// (`HWCAP_CPUID`).  Only features that the frontend can translate are reported
+const MIDR: u64 = 0x000f_0000; // architecture defined by ID registers, implementer reserved
+const ID_AA64PFR0: u64 = 0x00ff_0011; // EL0 and EL1 in AArch64 only, no FP and AdvSIMD
+const ID_AA64ISAR0: u64 = 0x0021_0000; // CRC32, LSE atomics
+
+// cache lines of CACHE_LINE_SIZE, no restrictions on instruction cache maintenance
+const CTR: u64 = 0x8444_c004;
+// DC ZVA is permitted and zeroes a cache line, log2 of the size in words
+const DCZID: u64 = 4;
+
+pub static SYSREGS: &[SysReg] = &[
+    SysReg {
+        name: "NZCV",
+        key: (3, 3, 4, 2, 0),
+        storage: SysRegStorage::Nzcv,
+        access: SysRegAccess::RW,
+    },
+    SysReg {
+        name: "FPCR",
+        key: (3, 3, 4, 4, 0),
+        storage: SysRegStorage::State(|| state_offset!(fpcr)),
+        access: SysRegAccess::RW,
+    },
+    SysReg {
+        name: "FPSR",
+        key: (3, 3, 4, 4, 1),
+        storage: SysRegStorage::State(|| state_offset!(fpsr)),
+        access: SysRegAccess::RW,
+    },
+    SysReg {
+        name: "TPIDR_EL0",
+        key: (3, 3, 13, 0, 2),
+        storage: SysRegStorage::State(|| state_offset!(tpidr_el0)),
+        access: SysRegAccess::RW,
+    },
+    SysReg {
+        name: "TPIDRRO_EL0",
+        key: (3, 3, 13, 0, 3),
+        storage: SysRegStorage::Const(0),
+        access: SysRegAccess::READ,
+    },
+    SysReg {
+        name: "CNTFRQ_EL0",
+        key: (3, 3, 14, 0, 0),
+        storage: SysRegStorage::Const(CNTFRQ),
+        access: SysRegAccess::READ,
+    },
+    SysReg {
+        name: "CNTVCT_EL0",
+        key: (3, 3, 14, 0, 2),
+        storage: SysRegStorage::Helper(helper_cntvct),
+        access: SysRegAccess::READ,
+    },
+    SysReg {
+        name: "CTR_EL0",
+        key: (3, 3, 0, 0, 1),
+        storage: SysRegStorage::Const(CTR),
+        access: SysRegAccess::READ,
+    },
+    SysReg {
+        name: "DCZID_EL0",
+        key: (3, 3, 0, 0, 7),
+        storage: SysRegStorage::Const(DCZID),
+        access: SysRegAccess::READ,
+    },
+    SysReg {
+        name: "MIDR_EL1",
+        key: (3, 0, 0, 0, 0),
+        storage: SysRegStorage::Const(MIDR),
+        access: SysRegAccess::READ,
+    },
+    SysReg {
+        name: "MPIDR_EL1",
+        key: (3, 0, 0, 0, 5),
+        storage: SysRegStorage::Const(1 << 31),
+        access: SysRegAccess::READ,
+    },
+    SysReg {
+        name: "REVIDR_EL1",
+        key: (3, 0, 0, 0, 6),
+        storage: SysRegStorage::Const(0),
+        access: SysRegAccess::READ,
+    },
+    SysReg {
+        name: "ID_AA64PFR0_EL1",
+        key: (3, 0, 0, 4, 0),
+        storage: SysRegStorage::Const(ID_AA64PFR0),
+        access: SysRegAccess::READ,
+    },
+    SysReg {
+        name: "ID_AA64PFR1_EL1",
+        key: (3, 0, 0, 4, 1),
+        storage: SysRegStorage::Const(0),
+        access: SysRegAccess::READ,
+    },
+    SysReg {
+        name: "ID_AA64ZFR0_EL1",
+        key: (3, 0, 0, 4, 4),
+        storage: SysRegStorage::Const(0),
+        access: SysRegAccess::READ,
+    },
+    SysReg {
+        name: "ID_AA64DFR0_EL1",
+        key: (3, 0, 0, 5, 0),
+        storage: SysRegStorage::Const(0),
+        access: SysRegAccess::READ,
+    },
+    SysReg {
+        name: "ID_AA64DFR1_EL1",
+        key: (3, 0, 0, 5, 1),
+        storage: SysRegStorage::Const(0),
+        access: SysRegAccess::READ,
+    },
+    SysReg {
+        name: "ID_AA64ISAR0_EL1",
+        key: (3, 0, 0, 6, 0),
+        storage: SysRegStorage::Const(ID_AA64ISAR0),
+        access: SysRegAccess::READ,
+    },
+    SysReg {
+        name: "ID_AA64ISAR1_EL1",
+        key: (3, 0, 0, 6, 1),
+        storage: SysRegStorage::Const(0),
+        access: SysRegAccess::READ,
+    },
+    SysReg {
+        name: "ID_AA64MMFR0_EL1",
+        key: (3, 0, 0, 7, 0),
+        storage: SysRegStorage::Const(0),
+        access: SysRegAccess::READ,
+    },
+    SysReg {
+        name: "ID_AA64MMFR1_EL1",
+        key: (3, 0, 0, 7, 1),
+        storage: SysRegStorage::Const(0),
+        access: SysRegAccess::READ,
+    },
+    SysReg {
+        name: "ID_AA64MMFR2_EL1",
+        key: (3, 0, 0, 7, 2),
+        storage: SysRegStorage::Const(0),
+        access: SysRegAccess::READ,
+    },
+    SysReg {
+        name: "DC ZVA",
+        key: (1, 3, 7, 4, 1),
+        storage: SysRegStorage::DcZva,
+        access: SysRegAccess::WRITE,
+    },
+    SysReg {
+        name: "IC IVAU",
+        key: (1, 3, 7, 5, 1),
+        storage: SysRegStorage::IcIvau,
+        access: SysRegAccess::WRITE,
+    },
+    SysReg {
+        name: "DC CVAC",
+        key: (1, 3, 7, 10, 1),
+        storage: SysRegStorage::Nop,
+        access: SysRegAccess::WRITE,
+    },
+    SysReg {
+        name: "DC CVAU",
+        key: (1, 3, 7, 11, 1),
+        storage: SysRegStorage::Nop,
+        access: SysRegAccess::WRITE,
+    },
+    SysReg {
+        name: "DC CVAP",
+        key: (1, 3, 7, 12, 1),
+        storage: SysRegStorage::Nop,
+        access: SysRegAccess::WRITE,
+    },
+    SysReg {
+        name: "DC CVADP",
+        key: (1, 3, 7, 13, 1),
+        storage: SysRegStorage::Nop,
+        access: SysRegAccess::WRITE,
+    },
+    SysReg {
+        name: "DC CIVAC",
+        key: (1, 3, 7, 14, 1),
+        storage: SysRegStorage::Nop,
+        access: SysRegAccess::WRITE,
+    },
+];
+
+/// Look up a system register or system instruction by its encoding.
+pub fn find_sysreg(op0: u32, op1: u32, crn: u32, crm: u32, op2: u32) -> Option<&'static SysReg> {
+    SYSREGS.iter().find(|r| r.key == (op0, op1, crn, crm, op2))
+}
+
+extern "C" fn helper_cntvct() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts);
+    }
+    ts.tv_sec as u64 * CNTFRQ + ts.tv_nsec as u64
+}
@@ -2,8 +2,12 @@
 //
 // SPDX-License-Identifier: BSD-3-Clause
 
+use super::facility::*;
+use super::sysreg::*;
 use super::*;
 
+use log::*;
+
 pub fn disas_system<R: HostStorage>(
     ctx: &mut Arm64GuestContext<R>,
     insn: InsnType,
@@ -64,9 +68,43 @@ fn handle_sync<R: HostStorage>(
     op2: u32,
     crm: u32,
 ) -> Result<(), DisasException> {
-    Err(DisasException::Unexpected(
-        "handle_sync work in progress".to_owned(),
-    ))
+    if op1 != 3 {
+        return unallocated(ctx, insn);
+    }
+
+    match op2 {
+        2 => {
+            // clrex: clear the exclusive monitor
+            let excl_addr = Rc::clone(&ctx.excl_addr);
+            let open = ctx.alloc_u64(!0);
+            Op::push_mov(ctx, &excl_addr, &open);
+            Ok(())
+        }
+        4 | 5 => {
+            // dsb / dmb: a dsb has no further effect than a dmb without devices
+            let kind = match crm & 3 {
+                1 => BarrierOp::ACQUIRE, // reads
+                2 => BarrierOp::ST_ST,   // writes
+                _ => BarrierOp::ALL,
+            };
+            Op::push_fence(ctx, kind);
+            Ok(())
+        }
+        6 => {
+            // isb: end the block, so that the instructions following are fetched again
+            let next_pc = ctx.alloc_u64(ctx.next_pc() as u64);
+            do_end_tb_to_addr(ctx, &next_pc, false);
+            Err(DisasException::Continue(ctx.next_pc()))
+        }
+        7 if crm == 0 => {
+            // sb: no speculation barrier on the host, use a full barrier and end the block
+            Op::push_fence(ctx, BarrierOp::ALL);
+            let next_pc = ctx.alloc_u64(ctx.next_pc() as u64);
+            do_end_tb_to_addr(ctx, &next_pc, false);
+            Err(DisasException::Continue(ctx.next_pc()))
+        }
+        _ => unallocated(ctx, insn),
+    }
 }
 
 fn handle_msr_i<R: HostStorage>(
@@ -76,9 +114,17 @@ fn handle_msr_i<R: HostStorage>(
     op2: u32,
     crm: u32,
 ) -> Result<(), DisasException> {
-    Err(DisasException::Unexpected(
-        "handle_msr_i work in progress".to_owned(),
-    ))
+    match (op1 << 3 | op2, crm) {
+        (0x00, 0) => {
+            // cfinv
+            let (_, _, cf, _) = get_flags(ctx);
+            let one = ctx.alloc_u32(1);
+            Op::push_xorl(ctx, &cf, &cf, &one);
+            Ok(())
+        }
+        (0x19, _) | (0x1a, _) => Ok(()), // ssbs / dit, no effect on the emulation
+        _ => unallocated(ctx, insn),     // PSTATE fields not accessible from EL0
+    }
 }
 
 fn handle_sys<R: HostStorage>(
@@ -92,7 +138,130 @@ fn handle_sys<R: HostStorage>(
     crm: u32,
     rt: usize,
 ) -> Result<(), DisasException> {
-    Err(DisasException::Unexpected(
-        "handle_sys work in progress".to_owned(),
-    ))
+    let sysreg = match find_sysreg(op0, op1, crn, crm, op2) {
+        Some(r) => r,
+        None => return unallocated(ctx, insn),
+    };
+    let access = if isread {
+        SysRegAccess::READ
+    } else {
+        SysRegAccess::WRITE
+    };
+    if !sysreg.access.contains(access) {
+        return unallocated(ctx, insn);
+    }
+    trace!(
+        "{} {}",
+        match (op0, isread) {
+            (1, false) => "sys",
+            (1, true) => "sysl",
+            (_, false) => "msr",
+            (_, true) => "mrs",
+        },
+        sysreg.name
+    );
+
+    let rt = ctx.reg(rt);
+    match sysreg.storage {
+        SysRegStorage::Const(v) => {
+            let v = ctx.alloc_u64(v);
+            Op::push_mov(ctx, &rt, &v);
+        }
+        SysRegStorage::State(offset) => {
+            let reg = Rc::clone(&ctx.sysreg[&offset()]);
+            if isread {
+                Op::push_mov(ctx, &rt, &reg);
+            } else {
+                Op::push_mov(ctx, &reg, &rt);
+            }
+        }
+        SysRegStorage::Nzcv => {
+            if isread {
+                do_get_nzcv(ctx, &rt);
+            } else {
+                do_set_nzcv(ctx, &rt);
+            }
+        }
+        SysRegStorage::Helper(func) => Op::push_call(ctx, &rt, func),
+        SysRegStorage::Nop => {}
+        SysRegStorage::DcZva => do_dc_zva(ctx, &rt),
+        SysRegStorage::IcIvau => {
+            // the runtime drops the blocks translated from the line
+            let line = cache_line(ctx, &rt);
+            let next_pc = ctx.alloc_u64(ctx.next_pc() as u64);
+            Op::push_trap(ctx, TrapOp::INVALIDATE_CODE, &line, &next_pc);
+            return Err(DisasException::Continue(ctx.next_pc()));
+        }
+    }
+
+    Ok(())
+}
+
+// start of the cache line containing the address in `rt`
+fn cache_line<R: HostStorage>(ctx: &mut Arm64GuestContext<R>, rt: &Rc<KHVal<R>>) -> Rc<KHVal<R>> {
+    let addr = clean_data_tbi(ctx, rt);
+    let mask = ctx.alloc_u64(!(CACHE_LINE_SIZE - 1));
+    Op::push_and(ctx, &addr, &addr, &mask);
+    addr
+}
+
+// zero the cache line, which is the block size in DCZID_EL0
+fn do_dc_zva<R: HostStorage>(ctx: &mut Arm64GuestContext<R>, rt: &Rc<KHVal<R>>) {
+    let addr = cache_line(ctx, rt);
+    let zero = ctx.alloc_u64(0);
+    let eight = ctx.alloc_u64(8);
+    for i in 0..CACHE_LINE_SIZE / 8 {
+        if i != 0 {
+            Op::push_add(ctx, &addr, &addr, &eight);
+        }
+        do_ldst(ctx, false, false, false, 8, &zero, &addr);
+    }
+}
+
+// assemble NZCV from the flags, into bits 31 to 28
+fn do_get_nzcv<R: HostStorage>(ctx: &mut Arm64GuestContext<R>, rt: &Rc<KHVal<R>>) {
+    let (nf, zf, cf, vf) = get_flags(ctx);
+    let nzcv = ctx.alloc_val(ValueType::U64);
+    let tmp = ctx.alloc_val(ValueType::U64);
+
+    // bit 31, N
+    let bit = ctx.alloc_u64(1 << 31);
+    Op::push_extulq(ctx, &nzcv, &nf);
+    Op::push_and(ctx, &nzcv, &nzcv, &bit);
+    // bit 30, Z
+    let zero = ctx.alloc_u32(0);
+    Op::push_setc(ctx, &tmp, &zf, &zero, CondOp::EQ);
+    Op::push_depos(ctx, &nzcv, &nzcv, &tmp, 30, 1);
+    // bit 29, C
+    Op::push_extulq(ctx, &tmp, &cf);
+    Op::push_depos(ctx, &nzcv, &nzcv, &tmp, 29, 1);
+    // bit 28, V
+    Op::push_extulq(ctx, &tmp, &vf);
+    Op::push_extru(ctx, &tmp, &tmp, 31, 1);
+    Op::push_depos(ctx, &nzcv, &nzcv, &tmp, 28, 1);
+
+    Op::push_mov(ctx, rt, &nzcv);
+}
+
+// split NZCV in bits 31 to 28 into the flags
+fn do_set_nzcv<R: HostStorage>(ctx: &mut Arm64GuestContext<R>, rt: &Rc<KHVal<R>>) {
+    let (nf, zf, cf, vf) = get_flags(ctx);
+    let tmp = ctx.alloc_val(ValueType::U64);
+
+    // bit 31, N
+    let bit = ctx.alloc_u64(1 << 31);
+    Op::push_and(ctx, &tmp, rt, &bit);
+    Op::push_extrl(ctx, &nf, &tmp);
+    // bit 30, Z: zf is zero if Z is set
+    let bit = ctx.alloc_u64(1 << 30);
+    Op::push_andc(ctx, &tmp, &bit, rt);
+    Op::push_extrl(ctx, &zf, &tmp);
+    // bit 29, C
+    Op::push_extru(ctx, &tmp, rt, 29, 1);
+    Op::push_extrl(ctx, &cf, &tmp);
+    // bit 28, V
+    Op::push_extru(ctx, &tmp, rt, 28, 1);
+    let shift = ctx.alloc_u64(31);
+    Op::push_shl(ctx, &tmp, &tmp, &shift);
+    Op::push_extrl(ctx, &vf, &tmp);
 }
@@ -4,7 +4,8 @@
 
 use super::ir::storage::*;
 use crate::guest::{DisasException, TranslationBlock};
-use crate::runtime::{GuestMap, TrapHandler};
+use crate::runtime::GuestMap;
+use std::ops::Range;
 use std::rc::Weak;
 
 /// The DumpIR dummy backend for IR printout.
@@ -12,10 +13,30 @@ pub mod dump_ir;
 /// The LLVM backend.
 pub mod llvm;
 
+/// Status of an emitted block when it returns to the runtime.
+///
+/// Written by the trap that ends the block; see [`TrapOp`](../ir/op/struct.TrapOp.html) for the
+/// trap causes and values.
+#[repr(C)]
+#[derive(Default, Debug)]
+pub struct BlockExit {
+    /// Guest address to continue execution at.
+    pub pc: u64,
+    /// Trap cause.
+    pub cause: u64,
+    /// Per-trap-defined value.
+    pub val: u64,
+    /// Host address of a chained block to run next without going through the runtime; zero if
+    /// the block traps to the runtime.  Used by the backend only.
+    pub chained: u64,
+}
+
 /// An emitted block that can be executed on the host.
 pub trait HostBlock {
-    /// Run from start of block.
-    unsafe fn execute(&self);
+    /// Run from start of block on the guest CPU `state` until it traps to the runtime.
+    ///
+    /// `state` has to be the CPU state structure of the frontend that generated the block.
+    unsafe fn execute<S>(&self, state: &mut S) -> BlockExit;
 }
 
 /// Code generator functions to be invoked from the runtime.
@@ -39,13 +60,14 @@ pub trait HostContext {
     ) -> Self::BlockType;
 
     /// Initialize the global backend context.
-    fn init(guest_vm: GuestMap, handler: TrapHandler);
+    fn init(guest_vm: GuestMap);
     /// Retrieve the global backend context.
     fn get() -> &'static mut Self;
 
     /// Allocate new block in backend for code generation.
     ///
-    /// Must be called before any of the following for an emitted block.
+    /// Must be called before any of the following for an emitted block.  Backends may make `name`
+    /// unique, as the same guest code can be translated more than once.
     fn push_block(&mut self, name: &str, create_func: bool);
 
     /// Create a label value.
@@ -57,10 +79,23 @@ pub trait HostContext {
     /// Create a `f64` value.  Backends may implement caching to avoid allocating duplicate values.
     fn make_f64(&self, v: f64) -> Self::StorageType;
     /// Create a named value for fixed registers.
-    fn make_named(&self, name: String, ty: ValueType) -> Self::StorageType;
+    ///
+    /// The register is located at byte `offset` in the guest CPU state passed to the emitted
+    /// blocks.
+    fn make_named(&self, name: String, offset: usize, ty: ValueType) -> Self::StorageType;
 
-    /// Backend-specific routine for handling traps.
+    /// Chain the exit that the last executed block left through to the emitted block for `dest`.
+    ///
+    /// Later executions of the exit jump to the block for `dest` directly instead of trapping
+    /// into the runtime with `LOOKUP_TB`.  Nothing is done if the last exit is not a direct branch
+    /// to `dest`, or if no block for `dest` has been emitted.
+    fn chain(&mut self, dest: usize);
+    /// Unchain all exits into the blocks translated from guest code in `range`, and forget about
+    /// these blocks such that they are never chained to again.
     ///
-    /// Backend-irrelevant parts should go into `runtime::trap_handler`.
-    fn handle_trap(&mut self);
+    /// Returns the start addresses of the forgotten blocks.
+    fn unchain(&mut self, range: Range<usize>) -> Vec<usize>;
+
+    /// Dump the emitted code for debugging.
+    fn dump_code(&mut self);
 }
@@ -7,9 +7,10 @@
 use crate::guest::{DisasException, TranslationBlock};
 use crate::host::*;
 use crate::ir::storage::*;
-use crate::runtime::{GuestMap, TrapHandler};
+use crate::runtime::GuestMap;
 use std::cell::RefCell;
 use std::fmt::{Display, Error, Formatter};
+use std::ops::Range;
 use std::rc::Weak;
 
 /// Dummy context for IR printout.
@@ -81,8 +82,9 @@ impl HostStorage for DumpIRHostStorage {
 
 impl HostBlock for String {
     /// Dump the string contents of the dummy generated block.
-    unsafe fn execute(&self) {
+    unsafe fn execute<S>(&self, _: &mut S) -> BlockExit {
         print!("{}", self);
+        BlockExit::default()
     }
 }
 
@@ -108,7 +110,7 @@ impl HostContext for DumpIRHostContext {
         ret
     }
 
-    fn init(_: GuestMap, _: TrapHandler) {
+    fn init(_: GuestMap) {
         unsafe {
             DUMP_IR_CTX = Some(Self {
                 label_counter: RefCell::new(0),
@@ -142,11 +144,19 @@ impl HostContext for DumpIRHostContext {
         DumpIRHostStorage::ImmF64(v)
     }
 
-    fn make_named(&self, name: String, ty: ValueType) -> Self::StorageType {
+    fn make_named(&self, name: String, offset: usize, ty: ValueType) -> Self::StorageType {
         DumpIRHostStorage::Named(name)
     }
 
-    fn handle_trap(&mut self) {
-        unimplemented!()
+    fn chain(&mut self, dest: usize) {
+        // dumped blocks are never executed, nothing to chain
+    }
+
+    fn unchain(&mut self, range: Range<usize>) -> Vec<usize> {
+        Vec::new()
+    }
+
+    fn dump_code(&mut self) {
+        // the IR of each block is returned by emit_block, nothing is kept to dump
     }
 }
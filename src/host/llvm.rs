@@ -2,19 +2,25 @@
 //
 // SPDX-License-Identifier: BSD-3-Clause
 
+use inkwell::attributes::AttributeLoc;
+use inkwell::basic_block::BasicBlock;
 use inkwell::builder::Builder;
 use inkwell::context::Context;
 use inkwell::execution_engine::{ExecutionEngine, JitFunction};
 use inkwell::module::{Linkage, Module};
 use inkwell::targets::{InitializationConfig, Target};
-use inkwell::types::{FloatType, FunctionType, IntType};
-use inkwell::values::{BasicValue, BasicValueEnum, FloatValue, GlobalValue, IntValue};
-use inkwell::{AddressSpace, OptimizationLevel};
+use inkwell::types::{BasicType, BasicTypeEnum, FloatType, FunctionType, IntType};
+use inkwell::values::{
+    AnyValue, BasicValue, BasicValueEnum, FloatValue, FunctionValue, InstructionValue, IntValue,
+    PointerValue,
+};
+use inkwell::{AddressSpace, AtomicOrdering, AtomicRMWBinOp, IntPredicate, OptimizationLevel};
 
 use log::*;
 use std::cell::RefCell;
 use std::collections::{HashMap, VecDeque};
 use std::fmt::{Display, Error, Formatter};
+use std::ops::Range;
 use std::rc::{Rc, Weak};
 
 use crate::guest::*;
@@ -24,7 +30,7 @@ use crate::ir::storage::*;
 use crate::runtime::*;
 use bitflags::_core::cell::RefMut;
 
-type GuestFunc = unsafe extern "C" fn();
+type GuestFunc = unsafe extern "C" fn(*mut u8, *mut BlockExit);
 
 /// Code generator context for the LLVM backend.
 pub struct LLVMHostContext<'ctx> {
@@ -37,27 +43,52 @@ pub struct LLVMHostContext<'ctx> {
     i32_type: Option<IntType<'ctx>>,
     i64_type: Option<IntType<'ctx>>,
     f64_type: Option<FloatType<'ctx>>,
-    handler_type: Option<FunctionType<'ctx>>,
     guest_vm: GuestMap,
-    handler: TrapHandler,
-    global_map: RefCell<HashMap<GlobalValue<'ctx>, Option<IntValue<'ctx>>>>,
-    dump_reg_func: Option<JitFunction<'ctx, GuestFunc>>,
+    // values of the fixed registers cached in the current block, indexed by offset in the guest
+    // CPU state
+    fixed_map: RefCell<HashMap<usize, Option<BasicValueEnum<'ctx>>>>,
+    // direct exits of the emitted blocks
+    chain_slots: Vec<ChainSlot>,
+    // exit that the last block left through, as index into `chain_slots` plus one; zero if the
+    // exit cannot be chained
+    last_exit: Box<u64>,
+    // end address and host address of the emitted blocks, indexed by guest start address
+    blocks: HashMap<usize, (usize, u64)>,
+    // function of the block under construction
+    block_func: Option<FunctionValue<'ctx>>,
+    // number of blocks created so far.  Appended to the block names: MCJIT resolves symbols
+    // across all modules, so a block translated again under the same name would resolve to the
+    // code emitted first
+    block_count: usize,
+}
+
+// a direct exit of an emitted block that can be chained to the block of its destination
+struct ChainSlot {
+    // guest address of the destination
+    dest: usize,
+    // host address of the destination block, zero if not chained
+    target: Box<u64>,
 }
 
 #[derive(Debug, PartialEq)]
 /// LLVM Storage for IR registers.
 ///
-/// Largely resembles the LLVM value categories.  Per the SSA semantics, assigned values cannot be
-/// reassigned; a new one needs to be created instead.
+/// Largely resembles the LLVM value categories.  LLVM values are in SSA form and cannot be
+/// reassigned; when the frontend reuses a temporary as destination, the storage is simply rebound
+/// to the new LLVM value.  Constants cannot be written to.
 pub enum LLVMHostStorage<'ctx> {
     /// A not-yet used register.
     Empty,
-    /// A fixed register.
-    Global(GlobalValue<'ctx>),
-    /// Assigned int temporary value.
+    /// A fixed register, at the byte offset in the guest CPU state.
+    Fixed(Rc<str>, usize),
+    /// Int constant.
     IntV(IntValue<'ctx>),
-    /// Assigned float temporary value.
+    /// Float constant.
     FloatV(FloatValue<'ctx>),
+    /// Assigned temporary value.
+    Temp(BasicValueEnum<'ctx>),
+    /// Branch target in the current function.
+    Label(BasicBlock<'ctx>),
 }
 
 impl Default for LLVMHostStorage<'_> {
@@ -71,9 +102,11 @@ impl Display for LLVMHostStorage<'_> {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
         match self {
             LLVMHostStorage::Empty => write!(f, "_"),
-            LLVMHostStorage::Global(v) => write!(f, "{}", v.get_name().to_str().unwrap()),
+            LLVMHostStorage::Fixed(name, _) => write!(f, "{}", name),
             LLVMHostStorage::IntV(v) => write!(f, "{}", v.print_to_string()),
             LLVMHostStorage::FloatV(v) => write!(f, "{}", v.print_to_string()),
+            LLVMHostStorage::Temp(v) => write!(f, "{}", v.print_to_string()),
+            LLVMHostStorage::Label(b) => write!(f, "{}", b.get_name().to_str().unwrap()),
         }
     }
 }
@@ -82,131 +115,103 @@ impl HostStorage for LLVMHostStorage<'static> {
     type HostContext = LLVMHostContext<'static>;
 
     fn try_as_u32(&self) -> Option<u32> {
-        if let LLVMHostStorage::IntV(lv) = self {
-            lv.get_zero_extended_constant().map(|x| x as u32)
-        } else {
-            None
-        }
+        self.try_as_u64().map(|x| x as u32)
     }
 
     fn try_as_u64(&self) -> Option<u64> {
-        if let LLVMHostStorage::IntV(lv) = self {
-            lv.get_zero_extended_constant()
-        } else {
-            None
+        // temporaries may hold constants folded by the builder
+        match self {
+            LLVMHostStorage::IntV(lv) | LLVMHostStorage::Temp(BasicValueEnum::IntValue(lv)) => {
+                lv.get_zero_extended_constant()
+            }
+            _ => None,
         }
     }
 
     fn try_as_f64(&self) -> Option<f64> {
-        if let LLVMHostStorage::FloatV(lv) = self {
-            lv.get_constant().map(|x| x.0)
-        } else {
-            None
+        match self {
+            LLVMHostStorage::FloatV(lv) | LLVMHostStorage::Temp(BasicValueEnum::FloatValue(lv)) => {
+                lv.get_constant().map(|x| x.0)
+            }
+            _ => None,
         }
     }
 }
 
 impl HostBlock for JitFunction<'_, GuestFunc> {
     /// Execute the generated LLVM JIT function.
-    unsafe fn execute(&self) {
-        self.call()
+    ///
+    /// Chained blocks return the address of the next block instead of calling it; they are run
+    /// from here until a block traps to the runtime.  Only `musttail` guarantees that a call
+    /// does not grow the host stack, but inkwell can only mark calls as `tail`, which is a hint,
+    /// as the C API of LLVM 10 has no way to set the tail call kind.  The cost is a return to
+    /// this loop and an indirect call for every transition between chained blocks.
+    unsafe fn execute<S>(&self, state: &mut S) -> BlockExit {
+        let state = state as *mut S as *mut u8;
+        let mut exit = BlockExit::default();
+        self.call(state, &mut exit);
+        while exit.chained != 0 {
+            let func: GuestFunc = std::mem::transmute(std::mem::replace(&mut exit.chained, 0));
+            func(state, &mut exit);
+        }
+        exit
     }
 }
 
 mod codegen;
+mod helper;
 
 static mut LLVM_CTX: Option<LLVMHostContext> = None;
-static REG_INIT: u64 = 0;
-static REG_INIT_FP: f64 = 0.0;
 
 impl LLVMHostContext<'static> {
     fn store_context(&mut self) {
-        // store cached values back into global
-        for (k, v) in self.global_map.borrow_mut().iter_mut() {
+        // store cached values back into the CPU state
+        for (&offset, v) in self.fixed_map.borrow_mut().iter_mut() {
             if let Some(iv) = v {
                 debug!(
-                    "Emitting store {} -> {}",
+                    "Emitting store {} -> state+{:#x}",
                     iv.print_to_string(),
-                    k.get_name().to_str().unwrap()
+                    offset
                 );
-                self.builder.build_store(k.as_pointer_value(), *iv);
+                let ptr = self.fixed_ptr(offset, iv.get_type());
+                self.builder.build_store(ptr, *iv);
                 *v = None;
             }
         }
     }
 
-    fn dump_modules(&self) {
-        for x in &self.modules {
-            x.print_to_stderr();
-        }
+    // pointer to the fixed register of `ty` at `offset` in the CPU state
+    fn fixed_ptr(&self, offset: usize, ty: BasicTypeEnum<'static>) -> PointerValue<'static> {
+        let state = self
+            .current_func()
+            .get_nth_param(0)
+            .expect("no CPU state for block")
+            .into_pointer_value();
+        let offset = self.i64_type.unwrap().const_int(offset as u64, false);
+        let ptr = unsafe { self.builder.build_in_bounds_gep(state, &[offset], "") };
+        self.builder
+            .build_pointer_cast(ptr, ty.ptr_type(AddressSpace::Generic), "")
     }
 
-    fn dump_reg(&mut self) {
-        // check that all values are stored back into global
-        for (k, v) in self.global_map.borrow().iter() {
-            assert_eq!(*v, None, "found leaked value for global {:?}", k);
-        }
-
-        if let None = self.dump_reg_func {
-            // build func
-            let name = "trap_handler";
-            self.push_block(name, true);
-
-            let module = self.modules.last().unwrap();
-
-            let printf = module.get_function("printf").unwrap_or_else(|| {
-                let char_ptr_type = self.context.i8_type().ptr_type(AddressSpace::Generic);
-                let func_type = self
-                    .context
-                    .i32_type()
-                    .fn_type(&[char_ptr_type.into()], true);
-                module.add_function("printf", func_type, Some(Linkage::External))
-            });
+    fn current_func(&self) -> FunctionValue<'static> {
+        self.builder
+            .get_insert_block()
+            .and_then(|b| b.get_parent())
+            .expect("no function under construction")
+    }
 
-            let mut format_string = String::new();
-            let mut tagged_args = Vec::new();
-            for (k, _) in self.global_map.borrow().iter() {
-                let name = String::from(k.get_name().to_str().unwrap());
-                let val = self.builder.build_load(k.as_pointer_value(), "");
+    // start a new basic block in the current function.  Used after terminators so that later
+    // instructions always have a valid insertion point; the new block may be unreachable.
+    fn new_block(&mut self) -> BasicBlock<'static> {
+        let block = self.context.append_basic_block(self.current_func(), "");
+        self.builder.position_at_end(block);
+        block
+    }
 
-                tagged_args.push((name, val));
-            }
-            tagged_args.sort_by_key(|(n, _)| n.to_owned());
-            let (names, mut args): (Vec<_>, VecDeque<_>) = tagged_args.into_iter().unzip();
-            let names = names
-                .iter()
-                .map(|n| format!("{}=0x%016lx", n))
-                .collect::<Vec<_>>();
-            let mut format_string: String = names
-                .chunks(4)
-                .map(|c| c.join("\t"))
-                .collect::<Vec<_>>()
-                .join("\n");
-
-            format_string.push('\n');
-
-            args.push_front(BasicValueEnum::from(
-                self.builder
-                    .build_global_string_ptr(&format_string, "")
-                    .as_pointer_value(),
-            ));
-            let args = args.into_iter().collect::<Vec<_>>();
-
-            self.builder.build_call(printf, args.as_slice(), "");
-            self.builder.build_return(None);
-
-            unsafe {
-                let f: JitFunction<GuestFunc> = self
-                    .execution_engine
-                    .as_ref()
-                    .unwrap()
-                    .get_function(name)
-                    .unwrap();
-                self.dump_reg_func = Some(f);
-            }
+    fn dump_modules(&self) {
+        for x in &self.modules {
+            x.print_to_stderr();
         }
-
-        unsafe { self.dump_reg_func.as_ref().unwrap().call() }
     }
 }
 
@@ -224,24 +229,38 @@ impl HostContext for LLVMHostContext<'static> {
         exception: Option<DisasException>,
     ) -> Self::BlockType {
         // consume TB
-        for op in tb.ops.into_iter() {
+        let exits = [tb.direct_chain_idx, tb.aux_chain_idx];
+        for (i, op) in tb.ops.into_iter().enumerate() {
             debug!("Emitting {}", op);
-            self.dispatch(op);
+            match op {
+                Op::Trap { cause, val, next } if exits.contains(&Some(i)) => {
+                    self.gen_exit(cause, val, next)
+                }
+                op => self.dispatch(op),
+            }
         }
 
         // end block, insert return
+        // the TB may end without a trap (e.g. after an access fault), write back registers
+        self.store_context();
         self.builder.build_return(None);
 
+        let func = self.block_func.take().expect("no function for block");
+        let name = func.get_name().to_str().unwrap();
+        let engine = self.execution_engine.as_ref().unwrap();
+        let addr = engine
+            .get_function_address(name)
+            .expect("failed to get function from JIT engine");
+        self.blocks.insert(tb.start_pc, (tb.end_pc, addr as u64));
+
         unsafe {
-            self.execution_engine
-                .as_ref()
-                .unwrap()
+            engine
                 .get_function(name)
                 .expect("failed to get function from JIT engine")
         }
     }
 
-    fn init(guest_vm: GuestMap, handler: TrapHandler) {
+    fn init(guest_vm: GuestMap) {
         // FIXME(jsteward): there should be a better way to do this (without leaking)
         let context = Box::new(Context::create());
         let context = Box::leak(context);
@@ -256,22 +275,15 @@ impl HostContext for LLVMHostContext<'static> {
                 i32_type: None,
                 i64_type: None,
                 f64_type: None,
-                handler_type: None,
                 guest_vm,
-                handler,
-                global_map: Default::default(),
-                dump_reg_func: None,
+                fixed_map: Default::default(),
+                chain_slots: Vec::new(),
+                last_exit: Box::new(0),
+                blocks: HashMap::new(),
+                block_func: None,
+                block_count: 0,
             });
 
-            LLVM_CTX.as_mut().unwrap().fn_type = Some(
-                LLVM_CTX
-                    .as_mut()
-                    .unwrap()
-                    .context
-                    .void_type()
-                    .fn_type(&[], false),
-            );
-
             LLVM_CTX.as_mut().unwrap().i32_type =
                 Some(LLVM_CTX.as_mut().unwrap().context.i32_type());
             LLVM_CTX.as_mut().unwrap().i64_type =
@@ -281,16 +293,20 @@ impl HostContext for LLVMHostContext<'static> {
 
             LLVM_CTX.as_mut().unwrap().f64_type =
                 Some(LLVM_CTX.as_mut().unwrap().context.f64_type());
-            LLVM_CTX.as_mut().unwrap().handler_type = Some(
-                LLVM_CTX
-                    .as_mut()
-                    .unwrap()
-                    .context
-                    .void_type()
-                    .fn_type(&[i64_type.into(), i64_type.into()], false),
-            );
 
-            // create default module for guest fixed register initializer
+            // blocks take a pointer to the CPU state, accessed by byte offsets, and a pointer to the
+            // `BlockExit` to fill in, accessed as an array of i64
+            let i8_type = LLVM_CTX.as_mut().unwrap().context.i8_type();
+            LLVM_CTX.as_mut().unwrap().fn_type =
+                Some(LLVM_CTX.as_mut().unwrap().context.void_type().fn_type(
+                    &[
+                        i8_type.ptr_type(AddressSpace::Generic).into(),
+                        i64_type.ptr_type(AddressSpace::Generic).into(),
+                    ],
+                    false,
+                ));
+
+            // create default module for the JIT engine
             LLVM_CTX.as_mut().unwrap().push_block("default", false);
         }
     }
@@ -300,7 +316,9 @@ impl HostContext for LLVMHostContext<'static> {
     }
 
     fn push_block(&mut self, name: &str, create_func: bool) {
-        self.modules.push(self.context.create_module(name));
+        let name = format!("{}_{}", name, self.block_count);
+        self.block_count += 1;
+        self.modules.push(self.context.create_module(&name));
         let module = self.modules.last().expect("failed to create module");
 
         if let None = self.execution_engine {
@@ -318,19 +336,30 @@ impl HostContext for LLVMHostContext<'static> {
         }
 
         if create_func {
-            let func = module.add_function(name, self.fn_type.unwrap(), None);
+            let func = module.add_function(&name, self.fn_type.unwrap(), None);
+            // the JIT engine targets the baseline x86-64 CPU, which lacks the 128-bit compare and
+            // exchange needed for `Cmpxchg2`
+            let cx16 = self
+                .context
+                .create_string_attribute("target-features", "+cx16");
+            func.add_attribute(AttributeLoc::Function, cx16);
 
             let basic_block = self.context.append_basic_block(func, "entry");
             self.builder.position_at_end(basic_block);
+            self.block_func = Some(func);
         }
     }
 
     fn make_label(&self) -> Self::StorageType {
-        unimplemented!()
+        // labels are only allocated during disassembly, when the function of the TB exists
+        let block = self
+            .context
+            .append_basic_block(self.current_func(), "label");
+        LLVMHostStorage::Label(block)
     }
 
     fn make_u32(&self, v: u32) -> Self::StorageType {
-        unimplemented!()
+        LLVMHostStorage::IntV(self.i32_type.unwrap().const_int(v as u64, false))
     }
 
     fn make_u64(&self, v: u64) -> Self::StorageType {
@@ -338,38 +367,49 @@ impl HostContext for LLVMHostContext<'static> {
     }
 
     fn make_f64(&self, v: f64) -> Self::StorageType {
-        unimplemented!()
+        LLVMHostStorage::FloatV(self.f64_type.unwrap().const_float(v))
     }
 
-    fn make_named(&self, name: String, ty: ValueType) -> Self::StorageType {
-        let module = self.modules.last().expect("failed to get current module");
-        let glb = match ty {
-            ValueType::U32 => {
-                let g = module.add_global(self.i32_type.unwrap(), None, name.as_ref());
-                g.set_initializer(&self.i32_type.unwrap().const_int(REG_INIT, false));
-                g
-            }
-            ValueType::U64 => {
-                let g = module.add_global(self.i64_type.unwrap(), None, name.as_ref());
-                g.set_initializer(&self.i64_type.unwrap().const_int(REG_INIT, false));
-                g
-            }
-            ValueType::F64 => {
-                let g = module.add_global(self.f64_type.unwrap(), None, name.as_ref());
-                g.set_initializer(&self.f64_type.unwrap().const_float(REG_INIT_FP));
-                g
-            }
-            _ => unreachable!(),
-        };
-        // record global
-        self.global_map.borrow_mut().insert(glb, None);
-        LLVMHostStorage::Global(glb)
+    fn make_named(&self, name: String, offset: usize, ty: ValueType) -> Self::StorageType {
+        // record register for caching
+        self.fixed_map.borrow_mut().insert(offset, None);
+        LLVMHostStorage::Fixed(name.into(), offset)
+    }
+
+    fn chain(&mut self, dest: usize) {
+        let exit = std::mem::replace(&mut *self.last_exit, 0) as usize;
+        if exit == 0 {
+            return;
+        }
+        let slot = &mut self.chain_slots[exit - 1];
+        if slot.dest != dest {
+            return;
+        }
+        if let Some(&(_, addr)) = self.blocks.get(&dest) {
+            debug!("Chaining exit {} to block for guest {:#x}", exit - 1, dest);
+            *slot.target = addr;
+        }
     }
 
-    fn handle_trap(&mut self) {
-        info!("Dumping registers");
-        self.dump_reg();
+    fn unchain(&mut self, range: Range<usize>) -> Vec<usize> {
+        let removed = self
+            .blocks
+            .iter()
+            .filter(|(&start, &(end, _))| start < range.end && end > range.start)
+            .map(|(&start, _)| start)
+            .collect::<Vec<_>>();
+        for start in &removed {
+            self.blocks.remove(start);
+        }
+        for slot in &mut self.chain_slots {
+            if removed.contains(&slot.dest) {
+                *slot.target = 0;
+            }
+        }
+        removed
+    }
 
+    fn dump_code(&mut self) {
         info!("Dumping modules generated so far");
         self.dump_modules();
     }
@@ -11,61 +11,696 @@ macro_rules! read_value {
     ($self:expr, $rs:expr) => {
         match *$rs.storage.borrow() {
             LLVMHostStorage::Empty => panic!("trying to use empty value"),
-            LLVMHostStorage::Global(v) => {
+            LLVMHostStorage::Fixed(_, offset) => {
                 // check if value has previously been read
                 match $self
-                    .global_map
+                    .fixed_map
                     .borrow_mut()
-                    .get_mut(&v)
-                    .expect("untracked global")
+                    .get_mut(&offset)
+                    .expect("untracked fixed register")
                 {
                     Some(cv) => *cv,
                     o @ None => {
-                        let temp = $self
-                            .builder
-                            .build_load(v.as_pointer_value(), "")
-                            .into_int_value();
+                        let ptr = $self.fixed_ptr(offset, $self.value_type($rs.ty));
+                        let temp = $self.builder.build_load(ptr, "");
                         *o = Some(temp);
                         temp
                     }
                 }
             }
-            LLVMHostStorage::IntV(v) => v,
-            _ => panic!("not implemented"),
+            LLVMHostStorage::IntV(v) => BasicValueEnum::from(v),
+            LLVMHostStorage::FloatV(v) => BasicValueEnum::from(v),
+            LLVMHostStorage::Temp(v) => v,
+            LLVMHostStorage::Label(_) => panic!("trying to use label as value"),
         }
     };
 }
 
+macro_rules! read_int {
+    ($self:expr, $rs:expr) => {
+        read_value!($self, $rs).into_int_value()
+    };
+}
+
+macro_rules! read_float {
+    ($self:expr, $rs:expr) => {
+        read_value!($self, $rs).into_float_value()
+    };
+}
+
+macro_rules! read_imm {
+    ($rs:expr) => {
+        $rs.storage
+            .borrow()
+            .try_as_u64()
+            .expect("expected immediate value")
+    };
+}
+
 macro_rules! store_result {
     ($self:expr, $rd:expr, $result:expr) => {
+        let result = BasicValueEnum::from($result);
         let mut rd_storage = $rd.storage.borrow_mut();
         match *rd_storage {
-            LLVMHostStorage::Empty => *rd_storage = LLVMHostStorage::IntV($result),
-            LLVMHostStorage::Global(v) => {
-                // store into cache area in global_map
+            // temporaries may be reused as destination by the frontend; rebind them
+            LLVMHostStorage::Empty | LLVMHostStorage::Temp(_) => {
+                *rd_storage = LLVMHostStorage::Temp(result)
+            }
+            LLVMHostStorage::Fixed(_, offset) => {
+                // store into cache area in fixed_map
                 *$self
-                    .global_map
+                    .fixed_map
                     .borrow_mut()
-                    .get_mut(&v)
-                    .expect("untracked global") = Some($result);
+                    .get_mut(&offset)
+                    .expect("untracked fixed register") = Some(result);
+            }
+            LLVMHostStorage::IntV(_) | LLVMHostStorage::FloatV(_) => {
+                panic!("ssa violation: trying to write to constant value")
+            }
+            LLVMHostStorage::Label(_) => panic!("trying to write to label"),
+        }
+    };
+}
+
+macro_rules! read_label {
+    ($rs:expr) => {
+        match *$rs.storage.borrow() {
+            LLVMHostStorage::Label(b) => b,
+            _ => panic!("trying to use non-label value as branch target"),
+        }
+    };
+}
+
+// map condition codes to LLVM integer predicates.  `ALWAYS` and `NEVER` have no comparison
+// counterpart and should be handled by the caller.
+fn cond_to_predicate(cc: CondOp) -> Option<IntPredicate> {
+    match cc {
+        CondOp::EQ => Some(IntPredicate::EQ),
+        CondOp::NE => Some(IntPredicate::NE),
+        CondOp::LT => Some(IntPredicate::SLT),
+        CondOp::GE => Some(IntPredicate::SGE),
+        CondOp::LE => Some(IntPredicate::SLE),
+        CondOp::GT => Some(IntPredicate::SGT),
+        CondOp::LTU => Some(IntPredicate::ULT),
+        CondOp::GEU => Some(IntPredicate::UGE),
+        CondOp::LEU => Some(IntPredicate::ULE),
+        CondOp::GTU => Some(IntPredicate::UGT),
+        CondOp::ALWAYS | CondOp::NEVER => None,
+        _ => unreachable!("bad condition code {:?}", cc),
+    }
+}
+
+// Combined acquire and release semantics are sequentially consistent.  Relaxed atomic accesses
+// are still single-copy atomic, which is monotonic in LLVM.
+fn mem_op_to_ordering(mem_op: MemOp) -> AtomicOrdering {
+    match (mem_op.get_acquire(), mem_op.get_release()) {
+        (false, false) => AtomicOrdering::Monotonic,
+        (true, false) => AtomicOrdering::Acquire,
+        (false, true) => AtomicOrdering::Release,
+        (true, true) => AtomicOrdering::SequentiallyConsistent,
+    }
+}
+
+impl LLVMHostContext<'static> {
+    // turn a load or store into an atomic access with the ordering in `mem_op`
+    fn set_atomic(&self, insn: InstructionValue<'static>, size: u64, mem_op: MemOp) {
+        // atomic accesses require explicit alignment
+        insn.set_alignment(size as u32).unwrap();
+        insn.set_atomic_ordering(mem_op_to_ordering(mem_op))
+            .unwrap();
+    }
+
+    // host pointer to guest memory at `addr` for an access of `size` bytes
+    fn guest_ptr(&self, addr: IntValue<'static>, size: u64) -> PointerValue<'static> {
+        let i64_type = self.i64_type.unwrap();
+
+        // calculate real address = offset + guest
+        let offset = self.guest_vm.borrow().as_ptr() as u64;
+        let offset = i64_type.const_int(offset, false);
+        let addr = self.builder.build_int_add(addr, offset, "");
+
+        let ptr_type = self
+            .context
+            .custom_width_int_type(size as u32 * 8)
+            .ptr_type(AddressSpace::Generic);
+        self.builder.build_int_to_ptr(addr, ptr_type, "")
+    }
+
+    // LLVM type of IR registers of `ty`
+    fn value_type(&self, ty: ValueType) -> BasicTypeEnum<'static> {
+        match ty {
+            ValueType::U32 => self.i32_type.unwrap().into(),
+            ValueType::U64 => self.i64_type.unwrap().into(),
+            ValueType::F64 => self.f64_type.unwrap().into(),
+            _ => unreachable!("no LLVM type for {:?}", ty),
+        }
+    }
+
+    // constant pointer to a host `u64` that outlives the emitted code
+    fn host_u64_ptr(&self, p: &u64) -> PointerValue<'static> {
+        let i64_type = self.i64_type.unwrap();
+        i64_type
+            .const_int(p as *const u64 as u64, false)
+            .const_to_pointer(i64_type.ptr_type(AddressSpace::Generic))
+    }
+
+    // pointer to the `BlockExit` of the current block
+    fn exit_ptr(&self) -> PointerValue<'static> {
+        self.current_func()
+            .get_nth_param(1)
+            .expect("no exit status for block")
+            .into_pointer_value()
+    }
+
+    // end the block with a `LOOKUP_TB` trap that is skipped once the exit is chained
+    pub(super) fn gen_exit(&mut self, cause: Reg, val: Reg, next: Reg) {
+        let dest = val.storage.borrow().try_as_u64();
+        let exit = match dest {
+            // indirect branches are not chained
+            None => 0,
+            Some(dest) => {
+                self.store_context();
+
+                let target = Box::new(0);
+                let target_ptr = self.host_u64_ptr(&target);
+                self.chain_slots.push(ChainSlot {
+                    dest: dest as usize,
+                    target,
+                });
+
+                let target = self.builder.build_load(target_ptr, "").into_int_value();
+                let zero = self.i64_type.unwrap().const_zero();
+                let chained = self
+                    .builder
+                    .build_int_compare(IntPredicate::NE, target, zero, "");
+                let current = self.builder.get_insert_block().unwrap();
+                let jump = self.new_block();
+                let trap = self.new_block();
+                self.builder.position_at_end(current);
+                self.builder.build_conditional_branch(chained, jump, trap);
+
+                // hand the next block to `execute`, which runs it without the runtime
+                self.builder.position_at_end(jump);
+                let field = unsafe {
+                    self.builder.build_in_bounds_gep(
+                        self.exit_ptr(),
+                        &[self.i64_type.unwrap().const_int(3, false)],
+                        "",
+                    )
+                };
+                self.builder.build_store(field, target);
+                self.builder.build_return(None);
+
+                self.builder.position_at_end(trap);
+                self.chain_slots.len() as u64
+            }
+        };
+
+        // tell the runtime which exit to chain
+        let last_exit = self.host_u64_ptr(&self.last_exit);
+        let exit = self.i64_type.unwrap().const_int(exit, false);
+        self.builder.build_store(last_exit, exit);
+        self.gen_trap(cause, val, next);
+    }
+
+    // call an integer LLVM intrinsic, declaring it in the current module if needed
+    fn call_intrinsic(
+        &self,
+        name: &str,
+        args: &[BasicValueEnum<'static>],
+        ret_type: IntType<'static>,
+    ) -> IntValue<'static> {
+        let func = self.declare_func(name, args, ret_type);
+        self.build_int_call(func, args)
+    }
+
+    // call an integer host function at `addr`, declaring it in the current module if needed
+    fn call_helper(
+        &self,
+        name: &str,
+        addr: usize,
+        args: &[BasicValueEnum<'static>],
+        ret_type: IntType<'static>,
+    ) -> IntValue<'static> {
+        let module = self.modules.last().expect("failed to get current module");
+        let func = module.get_function(name).unwrap_or_else(|| {
+            let func = self.declare_func(name, args, ret_type);
+            self.execution_engine
+                .as_ref()
+                .unwrap()
+                .add_global_mapping(&func, addr);
+            func
+        });
+        self.build_int_call(func, args)
+    }
+
+    fn declare_func(
+        &self,
+        name: &str,
+        args: &[BasicValueEnum<'static>],
+        ret_type: IntType<'static>,
+    ) -> FunctionValue<'static> {
+        let module = self.modules.last().expect("failed to get current module");
+        module.get_function(name).unwrap_or_else(|| {
+            let arg_types = args.iter().map(|a| a.get_type()).collect::<Vec<_>>();
+            let func_type = ret_type.fn_type(arg_types.as_slice(), false);
+            module.add_function(name, func_type, None)
+        })
+    }
+
+    fn build_int_call(
+        &self,
+        func: FunctionValue<'static>,
+        args: &[BasicValueEnum<'static>],
+    ) -> IntValue<'static> {
+        self.builder
+            .build_call(func, args, "")
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_int_value()
+    }
+
+    // evaluate `c1 _cc_ c2` into an `i1`
+    fn build_cond(&self, c1: Reg, c2: Reg, cc: Reg) -> IntValue<'static> {
+        let cc = CondOp::from_bits(read_imm!(cc)).unwrap();
+        let c1 = read_int!(self, c1);
+        let c2 = read_int!(self, c2);
+        let bool_type = self.context.bool_type();
+
+        match cc {
+            CondOp::ALWAYS => bool_type.const_int(1, false),
+            CondOp::NEVER => bool_type.const_int(0, false),
+            cc => {
+                let pred = cond_to_predicate(cc).unwrap();
+                self.builder.build_int_compare(pred, c1, c2, "")
             }
-            _ => panic!("ssa violation: trying to write to to initialized value"),
         }
+    }
+
+    // truncate `rs` to `bits` wide and extend back to 64 bits
+    fn gen_ext(&mut self, rd: Reg, rs: Reg, bits: u32, sign: bool) {
+        let i64_type = self.i64_type.unwrap();
+        let rs = read_int!(self, rs);
+
+        // the source may be either U32 or U64
+        let narrow = self.context.custom_width_int_type(bits);
+        let narrow = self.builder.build_int_cast(rs, narrow, "");
+        let result = if sign {
+            self.builder.build_int_s_extend(narrow, i64_type, "")
+        } else {
+            self.builder.build_int_z_extend(narrow, i64_type, "")
+        };
+        store_result!(self, rd, result);
+    }
+
+    // [rh:rl] = [ah:al] + [bh:bl], calculated in an integer type of double width
+    fn gen_add2_common(&mut self, rl: Reg, rh: Reg, al: Reg, ah: Reg, bl: Reg, bh: Reg) {
+        let al = read_int!(self, al);
+        let ah = read_int!(self, ah);
+        let bl = read_int!(self, bl);
+        let bh = read_int!(self, bh);
+
+        let half_type = al.get_type();
+        let width = half_type.get_bit_width();
+        let wide_type = self.context.custom_width_int_type(width * 2);
+        let shift = wide_type.const_int(width as u64, false);
+
+        let join = |lo, hi| {
+            let lo = self.builder.build_int_z_extend(lo, wide_type, "");
+            let hi = self.builder.build_int_z_extend(hi, wide_type, "");
+            let hi = self.builder.build_left_shift(hi, shift, "");
+            self.builder.build_or(hi, lo, "")
+        };
+        let a = join(al, ah);
+        let b = join(bl, bh);
+        let sum = self.builder.build_int_add(a, b, "");
+
+        let lo = self.builder.build_int_truncate(sum, half_type, "");
+        let hi = self.builder.build_right_shift(sum, shift, false, "");
+        let hi = self.builder.build_int_truncate(hi, half_type, "");
+        store_result!(self, rl, lo);
+        store_result!(self, rh, hi);
+    }
+
+    // high half of the double-width product of `a` and `b`
+    fn build_mul_high(
+        &self,
+        a: IntValue<'static>,
+        b: IntValue<'static>,
+        signed: bool,
+    ) -> IntValue<'static> {
+        let half_type = a.get_type();
+        let width = half_type.get_bit_width();
+        let wide_type = self.context.custom_width_int_type(width * 2);
+        let extend = |v| {
+            if signed {
+                self.builder.build_int_s_extend(v, wide_type, "")
+            } else {
+                self.builder.build_int_z_extend(v, wide_type, "")
+            }
+        };
+        let product = self.builder.build_int_mul(extend(a), extend(b), "");
+        let shift = wide_type.const_int(width as u64, false);
+        let hi = self.builder.build_right_shift(product, shift, false, "");
+        self.builder.build_int_truncate(hi, half_type, "")
+    }
+
+    fn gen_crc32_common(
+        &mut self,
+        rd: Reg,
+        acc: Reg,
+        val: Reg,
+        bytes: Reg,
+        name: &str,
+        helper: extern "C" fn(u64, u64, u64) -> u64,
+    ) {
+        let i64_type = self.i64_type.unwrap();
+        let acc = read_int!(self, acc);
+        let val = read_int!(self, val);
+        let bytes = i64_type.const_int(read_imm!(bytes), false);
+        let result = self.call_helper(
+            name,
+            helper as usize,
+            &[acc.into(), val.into(), bytes.into()],
+            i64_type,
+        );
+        store_result!(self, rd, result);
+    }
+}
+
+macro_rules! gen_binary {
+    ($name:ident, $rd:ident, $rs1:ident, $rs2:ident, $self:ident, $body:expr) => {
+        fn $name(&mut $self, $rd: Reg, $rs1: Reg, $rs2: Reg) {
+            let $rs1 = read_int!($self, $rs1);
+            let $rs2 = read_int!($self, $rs2);
+            let result = $body;
+            store_result!($self, $rd, result);
+        }
+    };
+    ($( $name:ident => $build:ident ),*) => {
+        $(
+            gen_binary!($name, rd, rs1, rs2, self, self.builder.$build(rs1, rs2, ""));
+        )*
+    };
+}
+
+macro_rules! gen_binary_float {
+    ($( $name:ident => $build:ident ),*) => {
+        $(
+            fn $name(&mut self, rd: Reg, rs1: Reg, rs2: Reg) {
+                let rs1 = read_float!(self, rs1);
+                let rs2 = read_float!(self, rs2);
+                let result = self.builder.$build(rs1, rs2, "");
+                store_result!(self, rd, result);
+            }
+        )*
     };
 }
 
 impl CodeGen<LLVMHostStorage<'static>> for LLVMHostContext<'static> {
+    fn gen_setlbl(&mut self, label: Reg) {
+        let label = read_label!(label);
+
+        // fall through into the label; the label block has multiple predecessors, so the cached
+        // fixed registers have to be written back first
+        self.store_context();
+        self.builder.build_unconditional_branch(label);
+        self.builder.position_at_end(label);
+    }
+
+    fn gen_brc(&mut self, dest: Reg, c1: Reg, c2: Reg, cc: Reg) {
+        let dest = read_label!(dest);
+        let cc = CondOp::from_bits(read_imm!(cc)).unwrap();
+
+        let c1 = read_int!(self, c1);
+        let c2 = read_int!(self, c2);
+
+        match cc {
+            CondOp::NEVER => {}
+            CondOp::ALWAYS => {
+                self.store_context();
+                self.builder.build_unconditional_branch(dest);
+                self.new_block();
+            }
+            cc => {
+                let pred = cond_to_predicate(cc).unwrap();
+                let cond = self.builder.build_int_compare(pred, c1, c2, "");
+
+                self.store_context();
+                let current = self.builder.get_insert_block().unwrap();
+                let fallthrough = self.new_block();
+                self.builder.position_at_end(current);
+                self.builder
+                    .build_conditional_branch(cond, dest, fallthrough);
+                self.builder.position_at_end(fallthrough);
+            }
+        }
+    }
+
     fn gen_mov(&mut self, rd: Reg, rs1: Reg) {
         let result = read_value!(self, rs1);
         store_result!(self, rd, result);
     }
 
+    fn gen_movl(&mut self, rd: Reg, rs1: Reg) {
+        self.gen_mov(rd, rs1);
+    }
+
+    fn gen_movd(&mut self, rd: Reg, rs1: Reg) {
+        self.gen_mov(rd, rs1);
+    }
+
+    fn gen_neg(&mut self, rd: Reg, rs1: Reg) {
+        let rs1 = read_int!(self, rs1);
+        let result = self.builder.build_int_neg(rs1, "");
+        store_result!(self, rd, result);
+    }
+
+    fn gen_negl(&mut self, rd: Reg, rs1: Reg) {
+        self.gen_neg(rd, rs1);
+    }
+
+    fn gen_not(&mut self, rd: Reg, rs1: Reg) {
+        let rs1 = read_int!(self, rs1);
+        let result = self.builder.build_not(rs1, "");
+        store_result!(self, rd, result);
+    }
+
+    fn gen_bswap(&mut self, rd: Reg, rs1: Reg) {
+        let i64_type = self.i64_type.unwrap();
+        let rs1 = read_int!(self, rs1);
+        let result = self.call_intrinsic("llvm.bswap.i64", &[rs1.into()], i64_type);
+        store_result!(self, rd, result);
+    }
+
+    fn gen_rbit(&mut self, rd: Reg, rs1: Reg) {
+        let i64_type = self.i64_type.unwrap();
+        let rs1 = read_int!(self, rs1);
+        let result = self.call_intrinsic("llvm.bitreverse.i64", &[rs1.into()], i64_type);
+        store_result!(self, rd, result);
+    }
+
+    fn gen_cls(&mut self, rd: Reg, rs1: Reg) {
+        let i64_type = self.i64_type.unwrap();
+        let rs1 = read_int!(self, rs1);
+        // clear the sign bits by xor with the sign, such that they are counted as leading zeroes
+        let sign = self
+            .builder
+            .build_right_shift(rs1, i64_type.const_int(63, false), true, "");
+        let cleared = self.builder.build_xor(rs1, sign, "");
+        let undef_zero = self.context.bool_type().const_int(0, false);
+        let count = self.call_intrinsic(
+            "llvm.ctlz.i64",
+            &[cleared.into(), undef_zero.into()],
+            i64_type,
+        );
+        let result = self
+            .builder
+            .build_int_sub(count, i64_type.const_int(1, false), "");
+        store_result!(self, rd, result);
+    }
+
+    fn gen_extulq(&mut self, rd: Reg, rs: Reg) {
+        self.gen_ext(rd, rs, 32, false);
+    }
+
+    fn gen_extslq(&mut self, rd: Reg, rs: Reg) {
+        self.gen_ext(rd, rs, 32, true);
+    }
+
+    fn gen_extuwq(&mut self, rd: Reg, rs: Reg) {
+        self.gen_ext(rd, rs, 16, false);
+    }
+
+    fn gen_extswq(&mut self, rd: Reg, rs: Reg) {
+        self.gen_ext(rd, rs, 16, true);
+    }
+
+    fn gen_extubq(&mut self, rd: Reg, rs: Reg) {
+        self.gen_ext(rd, rs, 8, false);
+    }
+
+    fn gen_extsbq(&mut self, rd: Reg, rs: Reg) {
+        self.gen_ext(rd, rs, 8, true);
+    }
+
+    fn gen_extrl(&mut self, rd: Reg, rs: Reg) {
+        let i32_type = self.i32_type.unwrap();
+        let rs = read_int!(self, rs);
+        let result = self.builder.build_int_cast(rs, i32_type, "");
+        store_result!(self, rd, result);
+    }
+
+    fn gen_extrh(&mut self, rd: Reg, rs: Reg) {
+        let i32_type = self.i32_type.unwrap();
+        let i64_type = self.i64_type.unwrap();
+        let rs = read_int!(self, rs);
+        let high = self
+            .builder
+            .build_right_shift(rs, i64_type.const_int(32, false), false, "");
+        let result = self.builder.build_int_truncate(high, i32_type, "");
+        store_result!(self, rd, result);
+    }
+
+    gen_binary! {
+        gen_add => build_int_add,
+        gen_sub => build_int_sub,
+        gen_subl => build_int_sub,
+        gen_mul => build_int_mul,
+        gen_div => build_int_signed_div,
+        gen_divu => build_int_unsigned_div,
+        gen_rem => build_int_signed_rem,
+        gen_remu => build_int_unsigned_rem,
+        gen_and => build_and,
+        gen_andl => build_and,
+        gen_or => build_or,
+        gen_orl => build_or,
+        gen_xor => build_xor,
+        gen_xorl => build_xor,
+        gen_shl => build_left_shift
+    }
+
+    gen_binary!(gen_mulsh, rd, rs1, rs2, self, {
+        self.build_mul_high(rs1, rs2, true)
+    });
+    gen_binary!(gen_muluh, rd, rs1, rs2, self, {
+        self.build_mul_high(rs1, rs2, false)
+    });
+    gen_binary!(gen_shr, rd, rs1, rs2, self, {
+        self.builder.build_right_shift(rs1, rs2, false, "")
+    });
+    gen_binary!(gen_sar, rd, rs1, rs2, self, {
+        self.builder.build_right_shift(rs1, rs2, true, "")
+    });
+    gen_binary!(gen_sarl, rd, rs1, rs2, self, {
+        self.builder.build_right_shift(rs1, rs2, true, "")
+    });
+    gen_binary!(gen_andc, rd, rs1, rs2, self, {
+        let rs2 = self.builder.build_not(rs2, "");
+        self.builder.build_and(rs1, rs2, "")
+    });
+    gen_binary!(gen_andcl, rd, rs1, rs2, self, {
+        let rs2 = self.builder.build_not(rs2, "");
+        self.builder.build_and(rs1, rs2, "")
+    });
+    gen_binary!(gen_orc, rd, rs1, rs2, self, {
+        let rs2 = self.builder.build_not(rs2, "");
+        self.builder.build_or(rs1, rs2, "")
+    });
+    gen_binary!(gen_eqv, rd, rs1, rs2, self, {
+        let result = self.builder.build_xor(rs1, rs2, "");
+        self.builder.build_not(result, "")
+    });
+    gen_binary!(gen_nand, rd, rs1, rs2, self, {
+        let result = self.builder.build_and(rs1, rs2, "");
+        self.builder.build_not(result, "")
+    });
+    gen_binary!(gen_nor, rd, rs1, rs2, self, {
+        let result = self.builder.build_or(rs1, rs2, "");
+        self.builder.build_not(result, "")
+    });
+    gen_binary!(gen_clz, rd, rs1, rs2, self, {
+        // rs2 is the result for zero input
+        let i64_type = self.i64_type.unwrap();
+        let is_zero =
+            self.builder
+                .build_int_compare(IntPredicate::EQ, rs1, i64_type.const_int(0, false), "");
+        let undef_zero = self.context.bool_type().const_int(0, false);
+        let count =
+            self.call_intrinsic("llvm.ctlz.i64", &[rs1.into(), undef_zero.into()], i64_type);
+        self.builder
+            .build_select(is_zero, rs2, count, "")
+            .into_int_value()
+    });
+    gen_binary!(gen_ctz, rd, rs1, rs2, self, {
+        // rs2 is the result for zero input
+        let i64_type = self.i64_type.unwrap();
+        let is_zero =
+            self.builder
+                .build_int_compare(IntPredicate::EQ, rs1, i64_type.const_int(0, false), "");
+        let undef_zero = self.context.bool_type().const_int(0, false);
+        let count =
+            self.call_intrinsic("llvm.cttz.i64", &[rs1.into(), undef_zero.into()], i64_type);
+        self.builder
+            .build_select(is_zero, rs2, count, "")
+            .into_int_value()
+    });
+    gen_binary!(gen_rotl, rd, rs1, rs2, self, {
+        // funnel shift with both halves being the same value is a rotation
+        let i64_type = self.i64_type.unwrap();
+        self.call_intrinsic(
+            "llvm.fshl.i64",
+            &[rs1.into(), rs1.into(), rs2.into()],
+            i64_type,
+        )
+    });
+    gen_binary!(gen_rotr, rd, rs1, rs2, self, {
+        let i64_type = self.i64_type.unwrap();
+        self.call_intrinsic(
+            "llvm.fshr.i64",
+            &[rs1.into(), rs1.into(), rs2.into()],
+            i64_type,
+        )
+    });
+    gen_binary!(gen_rotrl, rd, rs1, rs2, self, {
+        let i32_type = self.i32_type.unwrap();
+        self.call_intrinsic(
+            "llvm.fshr.i32",
+            &[rs1.into(), rs1.into(), rs2.into()],
+            i32_type,
+        )
+    });
+
+    gen_binary_float! {
+        gen_addd => build_float_add,
+        gen_subd => build_float_sub,
+        gen_muld => build_float_mul,
+        gen_divd => build_float_div
+    }
+
+    fn gen_extru(&mut self, rd: Reg, rs: Reg, ofs: Reg, len: Reg) {
+        let i64_type = self.i64_type.unwrap();
+        let rs = read_int!(self, rs);
+
+        let ofs = read_imm!(ofs);
+        let len = read_imm!(len);
+        let left_shift = i64_type.const_int(64 - len - ofs, false);
+        let right_shift = i64_type.const_int(64 - len, false);
+
+        let chop_high = self.builder.build_left_shift(rs, left_shift, "");
+        let result = self
+            .builder
+            .build_right_shift(chop_high, right_shift, false, "");
+        store_result!(self, rd, result);
+    }
+
     fn gen_extrs(&mut self, rd: Reg, rs: Reg, ofs: Reg, len: Reg) {
         let i64_type = self.i64_type.unwrap();
-        let rs = read_value!(self, rs);
+        let rs = read_int!(self, rs);
 
-        let ofs = ofs.storage.borrow().try_as_u64().unwrap();
-        let len = len.storage.borrow().try_as_u64().unwrap();
+        let ofs = read_imm!(ofs);
+        let len = read_imm!(len);
         let left_shift = i64_type.const_int(64 - len - ofs, false);
         let right_shift = i64_type.const_int(64 - len, false);
 
@@ -76,34 +711,133 @@ impl CodeGen<LLVMHostStorage<'static>> for LLVMHostContext<'static> {
         store_result!(self, rd, result);
     }
 
+    fn gen_depos(&mut self, rd: Reg, rs1: Reg, rs2: Reg, ofs: Reg, len: Reg) {
+        let i64_type = self.i64_type.unwrap();
+        let rs1 = read_int!(self, rs1);
+        let rs2 = read_int!(self, rs2);
+
+        let ofs = read_imm!(ofs);
+        let len = read_imm!(len);
+        assert!(len > 0 && ofs + len <= 64);
+        let mask = (!0u64 >> (64 - len)) << ofs;
+
+        let outside = self
+            .builder
+            .build_and(rs1, i64_type.const_int(!mask, false), "");
+        let inside = self
+            .builder
+            .build_left_shift(rs2, i64_type.const_int(ofs, false), "");
+        let inside = self
+            .builder
+            .build_and(inside, i64_type.const_int(mask, false), "");
+        let result = self.builder.build_or(outside, inside, "");
+        store_result!(self, rd, result);
+    }
+
+    fn gen_crc32(&mut self, rd: Reg, acc: Reg, val: Reg, bytes: Reg) {
+        self.gen_crc32_common(rd, acc, val, bytes, "helper_crc32", helper::helper_crc32);
+    }
+
+    fn gen_crc32c(&mut self, rd: Reg, acc: Reg, val: Reg, bytes: Reg) {
+        self.gen_crc32_common(rd, acc, val, bytes, "helper_crc32c", helper::helper_crc32c);
+    }
+
+    fn gen_setc(&mut self, rd: Reg, c1: Reg, c2: Reg, cc: Reg) {
+        let cond = self.build_cond(c1, c2, cc);
+        let rd_type = match rd.ty {
+            ValueType::U32 => self.i32_type.unwrap(),
+            ValueType::U64 => self.i64_type.unwrap(),
+            _ => unreachable!(),
+        };
+        let result = self.builder.build_int_z_extend(cond, rd_type, "");
+        store_result!(self, rd, result);
+    }
+
+    fn gen_movc(&mut self, rd: Reg, rs1: Reg, rs2: Reg, c1: Reg, c2: Reg, cc: Reg) {
+        let cond = self.build_cond(c1, c2, cc);
+        let rs1 = read_int!(self, rs1);
+        let rs2 = read_int!(self, rs2);
+        let result = self.builder.build_select(cond, rs1, rs2, "");
+        store_result!(self, rd, result);
+    }
+
+    fn gen_add2(&mut self, rl: Reg, rh: Reg, al: Reg, ah: Reg, bl: Reg, bh: Reg) {
+        self.gen_add2_common(rl, rh, al, ah, bl, bh);
+    }
+
+    fn gen_add2l(&mut self, rl: Reg, rh: Reg, al: Reg, ah: Reg, bl: Reg, bh: Reg) {
+        self.gen_add2_common(rl, rh, al, ah, bl, bh);
+    }
+
     fn gen_load(&mut self, rd: Reg, rs1: Reg, rs2: Reg) {
         let i64_type = self.i64_type.unwrap();
 
-        let rs1 = read_value!(self, rs1);
-        let mem_op = rs2.storage.borrow().try_as_u64().unwrap();
-        let mem_op = MemOp::from_bits(mem_op).unwrap();
+        let rs1 = read_int!(self, rs1);
+        let mem_op = MemOp::from_bits(read_imm!(rs2)).unwrap();
         let size: u64 = mem_op.get_size();
         let sign: bool = mem_op.get_sign();
 
-        // calculate real address = offset + guest
-        let offset = self.guest_vm.borrow().as_ptr() as u64;
-        let offset = i64_type.const_int(offset, false);
-        let addr = self.builder.build_int_add(rs1, offset, "");
+        let addr_ptr = self.guest_ptr(rs1, size);
+        let word = self.builder.build_load(addr_ptr, "").into_int_value();
+        if mem_op.intersects(MemOp::ORDER_MASK) {
+            self.set_atomic(word.as_instruction().unwrap(), size, mem_op);
+        }
 
-        let ptr_type = match size {
-            1 => self.context.i8_type(),
-            2 => self.context.i16_type(),
-            4 => self.context.i32_type(),
-            8 => self.context.i64_type(),
-            _ => unreachable!(),
+        let result = if size == 8 {
+            word
+        } else if sign {
+            self.builder.build_int_s_extend(word, i64_type, "")
+        } else {
+            self.builder.build_int_z_extend(word, i64_type, "")
+        };
+
+        store_result!(self, rd, result);
+    }
+
+    fn gen_store(&mut self, rd: Reg, rs1: Reg, rs2: Reg) {
+        let rd = read_int!(self, rd);
+        let rs1 = read_int!(self, rs1);
+        let mem_op = MemOp::from_bits(read_imm!(rs2)).unwrap();
+        let size: u64 = mem_op.get_size();
+
+        let addr_ptr = self.guest_ptr(rs1, size);
+        let word_type = self.context.custom_width_int_type(size as u32 * 8);
+        let word = self.builder.build_int_cast(rd, word_type, "");
+        let store = self.builder.build_store(addr_ptr, word);
+        if mem_op.intersects(MemOp::ORDER_MASK) {
+            self.set_atomic(store, size, mem_op);
         }
-        .ptr_type(AddressSpace::Generic);
+    }
 
-        let addr_ptr = self.builder.build_int_to_ptr(addr, ptr_type, "");
-        let word = self.builder.build_load(addr_ptr, "").into_int_value();
+    fn gen_cmpxchg(&mut self, rd: Reg, addr: Reg, cmp: Reg, new: Reg, mop: Reg) {
+        let i64_type = self.i64_type.unwrap();
 
-        let result = if sign {
-            self.builder.build_int_s_extend(word, i64_type, "")
+        let addr = read_int!(self, addr);
+        let cmp = read_int!(self, cmp);
+        let new = read_int!(self, new);
+        let mem_op = MemOp::from_bits(read_imm!(mop)).unwrap();
+        let size: u64 = mem_op.get_size();
+
+        let addr_ptr = self.guest_ptr(addr, size);
+        let word_type = self.context.custom_width_int_type(size as u32 * 8);
+        let cmp = self.builder.build_int_cast(cmp, word_type, "");
+        let new = self.builder.build_int_cast(new, word_type, "");
+
+        // the failing comparison is only a load, which can't be a release
+        let success = mem_op_to_ordering(mem_op);
+        let failure = mem_op_to_ordering(mem_op & !MemOp::RELEASE);
+        let pair = self
+            .builder
+            .build_cmpxchg(addr_ptr, cmp, new, success, failure)
+            .unwrap();
+        let word = self
+            .builder
+            .build_extract_value(pair, 0, "")
+            .unwrap()
+            .into_int_value();
+
+        let result = if size == 8 {
+            word
         } else {
             self.builder.build_int_z_extend(word, i64_type, "")
         };
@@ -111,29 +845,142 @@ impl CodeGen<LLVMHostStorage<'static>> for LLVMHostContext<'static> {
         store_result!(self, rd, result);
     }
 
-    fn gen_add(&mut self, rd: Reg, rs1: Reg, rs2: Reg) {
-        let rs1 = read_value!(self, rs1);
-        let rs2 = read_value!(self, rs2);
+    fn gen_cmpxchg2(
+        &mut self,
+        rl: Reg,
+        rh: Reg,
+        addr: Reg,
+        cl: Reg,
+        ch: Reg,
+        nl: Reg,
+        nh: Reg,
+        mop: Reg,
+    ) {
+        let i64_type = self.i64_type.unwrap();
+        let i128_type = self.context.i128_type();
+        let shift = i128_type.const_int(64, false);
 
-        let result = self.builder.build_int_add(rs1, rs2, "");
+        let addr = read_int!(self, addr);
+        let join = |lo, hi| {
+            let lo = self.builder.build_int_z_extend(lo, i128_type, "");
+            let hi = self.builder.build_int_z_extend(hi, i128_type, "");
+            let hi = self.builder.build_left_shift(hi, shift, "");
+            self.builder.build_or(hi, lo, "")
+        };
+        let cmp = join(read_int!(self, cl), read_int!(self, ch));
+        let new = join(read_int!(self, nl), read_int!(self, nh));
+        let mem_op = MemOp::from_bits(read_imm!(mop)).unwrap();
+
+        // lowered to `cmpxchg16b`, enabled for the blocks in `push_block`
+        let addr_ptr = self.guest_ptr(addr, 16);
+        let success = mem_op_to_ordering(mem_op);
+        let failure = mem_op_to_ordering(mem_op & !MemOp::RELEASE);
+        let pair = self
+            .builder
+            .build_cmpxchg(addr_ptr, cmp, new, success, failure)
+            .unwrap();
+        let old = self
+            .builder
+            .build_extract_value(pair, 0, "")
+            .unwrap()
+            .into_int_value();
+
+        let lo = self.builder.build_int_truncate(old, i64_type, "");
+        let hi = self.builder.build_right_shift(old, shift, false, "");
+        let hi = self.builder.build_int_truncate(hi, i64_type, "");
+        store_result!(self, rl, lo);
+        store_result!(self, rh, hi);
+    }
+
+    fn gen_atomicrmw(&mut self, rd: Reg, addr: Reg, val: Reg, aop: Reg, mop: Reg) {
+        let i64_type = self.i64_type.unwrap();
+
+        let addr = read_int!(self, addr);
+        let val = read_int!(self, val);
+        let atomic_op = AtomicOp::from_bits(read_imm!(aop)).unwrap();
+        let mem_op = MemOp::from_bits(read_imm!(mop)).unwrap();
+        let size: u64 = mem_op.get_size();
+
+        let addr_ptr = self.guest_ptr(addr, size);
+        let word_type = self.context.custom_width_int_type(size as u32 * 8);
+        let val = self.builder.build_int_cast(val, word_type, "");
+
+        let op = match atomic_op {
+            AtomicOp::XCHG => AtomicRMWBinOp::Xchg,
+            AtomicOp::ADD => AtomicRMWBinOp::Add,
+            AtomicOp::AND => AtomicRMWBinOp::And,
+            AtomicOp::OR => AtomicRMWBinOp::Or,
+            AtomicOp::XOR => AtomicRMWBinOp::Xor,
+            AtomicOp::SMAX => AtomicRMWBinOp::Max,
+            AtomicOp::SMIN => AtomicRMWBinOp::Min,
+            AtomicOp::UMAX => AtomicRMWBinOp::UMax,
+            AtomicOp::UMIN => AtomicRMWBinOp::UMin,
+            _ => unreachable!("bad atomic operation {:?}", atomic_op),
+        };
+        let word = self
+            .builder
+            .build_atomicrmw(op, addr_ptr, val, mem_op_to_ordering(mem_op))
+            .unwrap();
+
+        let result = if size == 8 {
+            word
+        } else {
+            self.builder.build_int_z_extend(word, i64_type, "")
+        };
+
+        store_result!(self, rd, result);
+    }
+
+    fn gen_fence(&mut self, kind: Reg) {
+        let kind = BarrierOp::from_bits(read_imm!(kind)).unwrap();
+        if kind.is_empty() {
+            return;
+        }
+
+        // Only a sequentially consistent fence orders earlier stores against later loads; on
+        // x86 this is the only kind that emits an instruction (`mfence`), as TSO already keeps
+        // the other orderings and the remaining fences only restrict compiler reordering.
+        let ordering = if kind.contains(BarrierOp::ST_LD) {
+            AtomicOrdering::SequentiallyConsistent
+        } else if BarrierOp::ACQUIRE.contains(kind) {
+            AtomicOrdering::Acquire
+        } else if BarrierOp::RELEASE.contains(kind) {
+            AtomicOrdering::Release
+        } else {
+            AtomicOrdering::AcquireRelease
+        };
+        self.builder.build_fence(ordering, 0, "");
+    }
+
+    fn gen_call(&mut self, rd: Reg, func: Reg) {
+        let i64_type = self.i64_type.unwrap();
+        let addr = read_imm!(func) as usize;
+
+        let result = self.call_helper(&format!("helper_{:x}", addr), addr, &[], i64_type);
         store_result!(self, rd, result);
     }
 
-    fn gen_trap(&mut self, cause: Reg, val: Reg) {
-        // store context before calling trap handler
+    fn gen_trap(&mut self, cause: Reg, val: Reg, next: Reg) {
+        // store context before leaving the block
         self.store_context();
 
-        let cause = read_value!(self, cause);
-        let val = read_value!(self, val);
+        let cause = read_int!(self, cause);
+        let val = read_int!(self, val);
+        let next = read_int!(self, next);
 
+        // fill in the `BlockExit`
+        let exit = self.exit_ptr();
         let i64_type = self.i64_type.unwrap();
-        let handler_type = self.handler_type.unwrap();
-        let handler_ptr_type = handler_type.ptr_type(AddressSpace::Generic);
-        let handler = i64_type
-            .const_int(self.handler as u64, false)
-            .const_to_pointer(handler_ptr_type);
+        for (i, v) in [next, cause, val].iter().enumerate() {
+            let field = unsafe {
+                self.builder
+                    .build_in_bounds_gep(exit, &[i64_type.const_int(i as u64, false)], "")
+            };
+            self.builder.build_store(field, *v);
+        }
 
-        self.builder
-            .build_call(handler, &[cause.into(), val.into()], "");
+        // return to the runtime
+        self.builder.build_return(None);
+        self.new_block();
     }
 }
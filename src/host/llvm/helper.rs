@@ -0,0 +1,78 @@
+// SPDX-FileCopyrightText: 2020 Pengcheng Xu <i@jsteward.moe>
+//
+// SPDX-License-Identifier: BSD-3-Clause
+
+// host functions called from the emitted code for operations that have no LLVM equivalent
+
+// bit-reflected polynomials
+const CRC32_POLY: u64 = 0xedb8_8320;
+const CRC32C_POLY: u64 = 0x82f6_3b78;
+
+fn crc32_common(acc: u64, val: u64, bytes: u64, poly: u64) -> u64 {
+    let val = if bytes < 8 {
+        val & !(!0 << (bytes * 8))
+    } else {
+        val
+    };
+    // the data beyond the lower 32 bits shifts into the CRC bit by bit
+    let mut crc = (acc & 0xffff_ffff) ^ val;
+    for _ in 0..bytes * 8 {
+        crc = (crc >> 1) ^ (poly & (crc & 1).wrapping_neg());
+    }
+    crc & 0xffff_ffff
+}
+
+pub extern "C" fn helper_crc32(acc: u64, val: u64, bytes: u64) -> u64 {
+    crc32_common(acc, val, bytes, CRC32_POLY)
+}
+
+pub extern "C" fn helper_crc32c(acc: u64, val: u64, bytes: u64) -> u64 {
+    crc32_common(acc, val, bytes, CRC32C_POLY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHECK_INPUT: &[u8] = b"123456789";
+
+    // the standard check values start from all ones and invert the result
+    fn checksum(helper: extern "C" fn(u64, u64, u64) -> u64, chunk: usize) -> u64 {
+        let acc = CHECK_INPUT.chunks(chunk).fold(0xffff_ffff, |acc, c| {
+            let mut val = [0; 8];
+            val[..c.len()].copy_from_slice(c);
+            helper(acc, u64::from_le_bytes(val), c.len() as u64)
+        });
+        acc ^ 0xffff_ffff
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        for &chunk in &[1, 2, 4, 8] {
+            assert_eq!(
+                checksum(helper_crc32, chunk),
+                0xcbf4_3926,
+                "chunk {}",
+                chunk
+            );
+        }
+    }
+
+    #[test]
+    fn crc32c_check_value() {
+        for &chunk in &[1, 2, 4, 8] {
+            assert_eq!(
+                checksum(helper_crc32c, chunk),
+                0xe306_9283,
+                "chunk {}",
+                chunk
+            );
+        }
+    }
+
+    #[test]
+    fn crc32_ignores_bytes_beyond_size() {
+        assert_eq!(helper_crc32(0, 0x1234_5678, 1), helper_crc32(0, 0x78, 1));
+        assert_eq!(helper_crc32c(0, !0, 4), helper_crc32c(0, 0xffff_ffff, 4));
+    }
+}
@@ -27,7 +27,9 @@ gen_ops! {
         /// Notable ones:
         /// - Mov: duplicate register (as in SSA paradigm)
         /// - Bswap: byte swap
-        unary: Neg, Not, Mov, Bswap;
+        /// - Rbit: bit reversal
+        /// - Cls: count leading sign bits, excluding the sign bit itself
+        unary: Neg, Not, Mov, Bswap, Rbit, Cls;
         /// Extend lower 32 bit (long) to full word (quad), unsigned (zero extension) or signed
         /// (sign extension).
         convert: ExtUlq, ExtSlq;
@@ -40,16 +42,25 @@ gen_ops! {
         /// Basic binary arithmetic operators for `U64` IR registers.
         ///
         /// Notable ones:
+        /// - Mulsh: high 64 bits of the 128-bit product, signed
+        /// - Muluh: high 64 bits of the 128-bit product, unsigned
+        /// - Div: divide, signed
+        /// - Divu: divide, unsigned
         /// - Rem: take remainder, signed
         /// - Remu: take remainder, unsigned
-        binary: Add, Sub, Mul, Div, Rem, Remu;
+        ///
+        /// Division by zero and signed overflow are undefined; the frontend has to handle them.
+        binary: Add, Sub, Mul, Mulsh, Muluh, Div, Divu, Rem, Remu;
         /// Basic logical (bitwise) arithmetic operators for `U64` IR registers.
         ///
         /// Notable ones:
-        /// - Andc: `!(a & b)`
-        /// - Orc: `!(a | b)`
-        /// - Clz: count leading zeroes
-        /// - Ctz: count tail zeroes
+        /// - Andc: `a & !b`
+        /// - Orc: `a | !b`
+        /// - Eqv: `!(a ^ b)`
+        /// - Nand: `!(a & b)`
+        /// - Nor: `!(a | b)`
+        /// - Clz: count leading zeroes, `rs2` if `rs1` is zero
+        /// - Ctz: count tail zeroes, `rs2` if `rs1` is zero
         binary: And, Or, Xor, Andc, Eqv, Nand, Nor, Orc, Clz, Ctz;
         /// Bit shift/rotations for `U64` IR registers.
         ///
@@ -64,9 +75,11 @@ gen_ops! {
         /// - `rs1`: memory access target
         /// - `rs2`: memory operation mode (see [`MemOp`](../storage/struct.MemOp.html))
         binary: Load, Store;
-        /// Trap to runtime.  Refer to [`TrapOP`](struct.TrapOp.html) for trap cause and value
-        /// definitions.
-        custom: Trap, cause, val;
+        /// Leave the translated block and trap to runtime.  Refer to
+        /// [`TrapOP`](struct.TrapOp.html) for trap cause and value definitions.
+        ///
+        /// The runtime continues execution at guest address `next` after handling the trap.
+        custom: Trap, cause, val, next;
         /// Signed bitfield extraction.
         ///
         /// Instruction format:
@@ -105,12 +118,63 @@ gen_ops! {
         ///
         /// `[rh:rl] = [ah:al] + [bh:bl]`
         custom: Add2, rl, rh, al, ah, bl, bh;
+        /// CRC-32 checksum update with the IEEE 802.3 polynomial.  The CRC is bit-reflected and
+        /// neither inverted before nor after the update.
+        ///
+        /// Instruction format:
+        /// - `rd`: destination register
+        /// - `acc`: CRC accumulated so far, in the lower 32 bits
+        /// - `val`: data to accumulate, lowest byte first
+        /// - `bytes`: number of bytes in `val`
+        custom: Crc32, rd, acc, val, bytes;
+        /// CRC-32 checksum update with the Castagnoli polynomial.  Same format as `Crc32`.
+        custom: Crc32c, rd, acc, val, bytes;
+        /// Atomic compare and exchange.  Stores `new` to the memory location at `addr` if it
+        /// holds `cmp`; the value found in memory is returned in `rd`, zero-extended.
+        ///
+        /// Instruction format:
+        /// - `rd`: old value in memory
+        /// - `addr`: memory access target
+        /// - `cmp`: expected value, truncated to the access size
+        /// - `new`: value to store, truncated to the access size
+        /// - `mop`: memory operation mode, including the memory ordering (see
+        ///   [`MemOp`](../storage/struct.MemOp.html))
+        custom: Cmpxchg, rd, addr, cmp, new, mop;
+        /// Double compare and exchange fused into a single 128-bit atomic operation for `U64` IR
+        /// registers.  Stores `[nh:nl]` to the 16 bytes at `addr` if they hold `[ch:cl]`; the
+        /// value found in memory is returned in `[rh:rl]`.  The access size in `mop` is ignored.
+        custom: Cmpxchg2, rl, rh, addr, cl, ch, nl, nh, mop;
+        /// Atomic read-modify-write.  Combines the memory location at `addr` with `val` as
+        /// specified by `aop` (see [`AtomicOp`](struct.AtomicOp.html)); the value found in memory
+        /// is returned in `rd`, zero-extended.
+        ///
+        /// Instruction format:
+        /// - `rd`: old value in memory
+        /// - `addr`: memory access target
+        /// - `val`: operand, truncated to the access size
+        /// - `aop`: operation to perform
+        /// - `mop`: memory operation mode, including the memory ordering (see
+        ///   [`MemOp`](../storage/struct.MemOp.html))
+        custom: AtomicRmw, rd, addr, val, aop, mop;
+        /// Memory barrier.  Orders the memory accesses before and after the barrier as specified
+        /// by `kind` (see [`BarrierOp`](struct.BarrierOp.html)).
+        custom: Fence, kind;
+        /// Call a host function `extern "C" fn() -> u64` that does not access the guest state.
+        ///
+        /// Instruction format:
+        /// - `rd`: return value of the function
+        /// - `func`: address of the function
+        custom: Call, rd, func;
         override_maker: Mov;
-        override_maker: Load, Store; // to accept MemOp
+        override_maker: Load, Store, Cmpxchg, Cmpxchg2; // to accept MemOp
+        override_maker: AtomicRmw; // to accept AtomicOp and MemOp
+        override_maker: Fence; // to accept BarrierOp
+        override_maker: Call; // to accept function pointer
         override_maker: Setc, Movc;  // to accept CondOp and to allow multiple types
         override_maker: Add, Sub, ExtUlq;    // simple optimizations
         override_maker: Trap;  // argument form, inject TB end
         override_maker: ExtrU, ExtrS, Depos; // to accept immediate value for ofs len
+        override_maker: Crc32, Crc32c; // to accept immediate value for bytes
     },
     ValueType::U32 {
         /// Basic unary operators for `U32` IR registers (`l` suffix).
@@ -173,6 +237,38 @@ impl CondOp {
     }
 }
 
+bitflags! {
+    /// Kinds of memory barriers, as the orderings enforced between the memory accesses before
+    /// and after the barrier.
+    pub struct BarrierOp: u64 {
+        const LD_LD = 0b0001;
+        const LD_ST = 0b0010;
+        const ST_LD = 0b0100;
+        const ST_ST = 0b1000;
+        const ALL   = 0b1111;
+        // aliases for one-way barriers
+        const ACQUIRE = Self::LD_LD.bits | Self::LD_ST.bits;
+        const RELEASE = Self::LD_ST.bits | Self::ST_ST.bits;
+    }
+}
+
+bitflags! {
+    /// Operations for use in atomic read-modify-write operators.
+    ///
+    /// Comparisons for the minimum and maximum are performed at the access size.
+    pub struct AtomicOp: u64 {
+        const XCHG  = 0;
+        const ADD   = 1;
+        const AND   = 2;
+        const OR    = 3;
+        const XOR   = 4;
+        const SMAX  = 5;
+        const SMIN  = 6;
+        const UMAX  = 7;
+        const UMIN  = 8;
+    }
+}
+
 bitflags! {
     /// Encoding for different trap causes.
     pub struct TrapOp: u64 {
@@ -187,18 +283,46 @@ bitflags! {
         /// The guest is attempting to perform an impossible memory access.
         ///
         /// Note that this only captures faulty addresses during the disassembly phase, e.g.
-        /// destinations that are outside of the guest virtual memory space (see [`GUEST_SIZE`]()).
+        /// instruction fetches from non-executable pages or outside of the guest virtual memory
+        /// space (see [`GUEST_SIZE`]()).
         ///
         /// Value meaning: guest address of the faulty memory access.
         const ACCESS_FAULT = 2;
         /// The guest is attempting to perform a system call.
         ///
-        /// Value meaning: host pointer to the syscall data block.
+        /// The syscall number and arguments are passed in the guest registers per the guest ABI.
+        /// The guest PC of the syscall instruction is passed as the next PC; execution resumes at
+        /// the following instruction.
+        ///
+        /// Value meaning: immediate of the syscall instruction.
         const SYSCALL = 3;
         /// The guest is attempting to perform a dynamically-linked function call.
         ///
-        /// Value meaning: guest address of the dynamic call site.
+        /// The function is substituted by a host function; the arguments and the return value
+        /// are passed in the guest registers per the guest ABI.  Execution continues in the stub,
+        /// which returns to the caller.
+        ///
+        /// Value meaning: guest address of the stub of the called function.
         const DYNAMIC = 4;
+        /// The guest executed a breakpoint instruction.
+        ///
+        /// The guest PC of the breakpoint instruction is passed as the next PC.
+        ///
+        /// Value meaning: immediate of the breakpoint instruction.
+        const BREAKPOINT = 5;
+        /// The guest is attempting to perform a semihosting call.
+        ///
+        /// The operation and its parameter are passed in the guest registers per the guest
+        /// semihosting ABI.
+        ///
+        /// Value meaning: guest PC of the semihosting call.
+        const SEMIHOSTING = 6;
+        /// The guest invalidated instruction cache lines after modifying code.
+        ///
+        /// The runtime drops the translated blocks that overlap the range, so that they are
+        /// translated again.  Value meaning: guest address of the first byte in the range.  The
+        /// range size is defined by the frontend.
+        const INVALIDATE_CODE = 7;
     }
 }
 
@@ -210,6 +334,9 @@ impl Display for TrapOp {
             TrapOp::ACCESS_FAULT => "access_fault",
             TrapOp::SYSCALL => "syscall",
             TrapOp::DYNAMIC => "dynamic",
+            TrapOp::BREAKPOINT => "breakpoint",
+            TrapOp::SEMIHOSTING => "semihosting",
+            TrapOp::INVALIDATE_CODE => "invalidate_code",
             _ => unreachable!(),
         };
 
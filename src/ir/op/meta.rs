@@ -31,6 +31,74 @@ impl<R: HostStorage> Op<R> {
         Op::_push_store(ctx, rd, addr, &mem_op);
     }
 
+    pub fn push_cmpxchg(
+        ctx: &mut impl DisasContext<R>,
+        rd: &Rc<KHVal<R>>,
+        addr: &Rc<KHVal<R>>,
+        cmp: &Rc<KHVal<R>>,
+        new: &Rc<KHVal<R>>,
+        mem_op: MemOp,
+    ) {
+        assert_eq!(rd.ty, ValueType::U64);
+        assert_eq!(addr.ty, ValueType::U64);
+        assert_eq!(cmp.ty, ValueType::U64);
+        assert_eq!(new.ty, ValueType::U64);
+        let mem_op = ctx.alloc_u64(mem_op.bits());
+        Op::_push_cmpxchg(ctx, rd, addr, cmp, new, &mem_op);
+    }
+
+    pub fn push_cmpxchg2(
+        ctx: &mut impl DisasContext<R>,
+        rl: &Rc<KHVal<R>>,
+        rh: &Rc<KHVal<R>>,
+        addr: &Rc<KHVal<R>>,
+        cl: &Rc<KHVal<R>>,
+        ch: &Rc<KHVal<R>>,
+        nl: &Rc<KHVal<R>>,
+        nh: &Rc<KHVal<R>>,
+        mem_op: MemOp,
+    ) {
+        assert_eq!(addr.ty, ValueType::U64);
+        for r in &[rl, rh, cl, ch, nl, nh] {
+            assert_eq!(r.ty, ValueType::U64);
+        }
+        let mem_op = ctx.alloc_u64(mem_op.bits());
+        Op::_push_cmpxchg2(ctx, rl, rh, addr, cl, ch, nl, nh, &mem_op);
+    }
+
+    pub fn push_atomicrmw(
+        ctx: &mut impl DisasContext<R>,
+        rd: &Rc<KHVal<R>>,
+        addr: &Rc<KHVal<R>>,
+        val: &Rc<KHVal<R>>,
+        atomic_op: AtomicOp,
+        mem_op: MemOp,
+    ) {
+        assert_eq!(rd.ty, ValueType::U64);
+        assert_eq!(addr.ty, ValueType::U64);
+        assert_eq!(val.ty, ValueType::U64);
+        let atomic_op = ctx.alloc_u64(atomic_op.bits());
+        let mem_op = ctx.alloc_u64(mem_op.bits());
+        Op::_push_atomicrmw(ctx, rd, addr, val, &atomic_op, &mem_op);
+    }
+
+    pub fn push_fence(ctx: &mut impl DisasContext<R>, kind: BarrierOp) {
+        trace!("push_fence");
+        let kind = ctx.alloc_u64(kind.bits());
+        Op::_push_fence(ctx, &kind);
+    }
+
+    pub fn push_call(
+        ctx: &mut impl DisasContext<R>,
+        rd: &Rc<KHVal<R>>,
+        func: extern "C" fn() -> u64,
+    ) {
+        trace!("push_call");
+        assert_eq!(rd.ty, ValueType::U64);
+        let func = ctx.alloc_u64(func as usize as u64);
+        Op::_push_call(ctx, rd, &func);
+    }
+
     pub fn push_setc(
         ctx: &mut impl DisasContext<R>,
         rd: &Rc<KHVal<R>>,
@@ -151,6 +219,32 @@ impl<R: HostStorage> Op<R> {
         Op::_push_depos(ctx, rd, rs1, rs2, &ofs, &len);
     }
 
+    pub fn push_crc32(
+        ctx: &mut impl DisasContext<R>,
+        rd: &Rc<KHVal<R>>,
+        acc: &Rc<KHVal<R>>,
+        val: &Rc<KHVal<R>>,
+        bytes: u64,
+    ) {
+        trace!("push_crc32");
+        assert!(bytes > 0 && bytes <= 8);
+        let bytes = ctx.alloc_u64(bytes);
+        Op::_push_crc32(ctx, rd, acc, val, &bytes);
+    }
+
+    pub fn push_crc32c(
+        ctx: &mut impl DisasContext<R>,
+        rd: &Rc<KHVal<R>>,
+        acc: &Rc<KHVal<R>>,
+        val: &Rc<KHVal<R>>,
+        bytes: u64,
+    ) {
+        trace!("push_crc32c");
+        assert!(bytes > 0 && bytes <= 8);
+        let bytes = ctx.alloc_u64(bytes);
+        Op::_push_crc32c(ctx, rd, acc, val, &bytes);
+    }
+
     pub fn push_mov(ctx: &mut impl DisasContext<R>, rd: &Rc<KHVal<R>>, rs: &Rc<KHVal<R>>) {
         assert_eq!(rd.ty, rs.ty);
         trace!("push_mov");
@@ -166,11 +260,16 @@ impl<R: HostStorage> Op<R> {
         }
     }
 
-    pub fn push_trap(ctx: &mut impl DisasContext<R>, cause: TrapOp, val: &Rc<KHVal<R>>) {
+    pub fn push_trap(
+        ctx: &mut impl DisasContext<R>,
+        cause: TrapOp,
+        val: &Rc<KHVal<R>>,
+        next: &Rc<KHVal<R>>,
+    ) {
         trace!("push_trap");
         let cause = ctx.alloc_u64(cause.bits);
         let new_val = ctx.alloc_val(val.ty);
         Op::push_mov(ctx, &new_val, val);
-        Op::_push_trap(ctx, &cause, &new_val);
+        Op::_push_trap(ctx, &cause, &new_val, next);
     }
 }
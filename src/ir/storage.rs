@@ -60,11 +60,12 @@ impl<R: HostStorage> KHVal<R> {
         }
     }
 
-    /// Allocate new named register from the frontend.
-    pub fn named(name: String, ty: ValueType) -> Self {
+    /// Allocate new named register from the frontend, located at byte `offset` in the guest CPU
+    /// state.
+    pub fn named(name: String, offset: usize, ty: ValueType) -> Self {
         Self {
             ty,
-            storage: RefCell::new(R::HostContext::get().make_named(name, ty)),
+            storage: RefCell::new(R::HostContext::get().make_named(name, offset, ty)),
         }
     }
 
@@ -136,6 +137,14 @@ bitflags! {
         const ALIGN_64   = 0b110 << 4;
         const ALIGN_MASK = 0b111 << 4;
 
+        // memory ordering, for atomic operations relaxed if neither is set, for loads and stores
+        // non-atomic if neither is set.  Loads can't have release and stores can't have acquire
+        // semantics on their own
+        const ACQUIRE    = 0b01 << 7;
+        const RELEASE    = 0b10 << 7;
+        const SEQ_CST    = 0b11 << 7;
+        const ORDER_MASK = 0b11 << 7;
+
         // aliases for operand types
         const UB = Self::SIZE_8.bits;
         const UW = Self::SIZE_16.bits;
@@ -188,4 +197,14 @@ impl MemOp {
     pub fn get_sign(&self) -> bool {
         (*self & Self::SIGN_EXTEND).bits != 0
     }
+
+    /// Retrieve whether an atomic operation has acquire semantics from `MemOp`.
+    pub fn get_acquire(&self) -> bool {
+        (*self & Self::ACQUIRE).bits != 0
+    }
+
+    /// Retrieve whether an atomic operation has release semantics from `MemOp`.
+    pub fn get_release(&self) -> bool {
+        (*self & Self::RELEASE).bits != 0
+    }
 }
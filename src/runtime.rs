@@ -4,6 +4,7 @@
 
 extern crate log;
 
+use crate::guest::arm64::{Arm64CpuState, CACHE_LINE_SIZE};
 use crate::guest::*;
 use crate::host::{HostBlock, HostContext};
 use crate::ir::op::TrapOp;
@@ -13,9 +14,12 @@ use memmap::{MmapMut, MmapOptions};
 
 use std::cell::{Ref, RefCell};
 use std::collections::{BTreeMap, HashMap, VecDeque};
-use std::path::Path;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
-use std::{env, fs};
+use std::{env, fs, process};
+
+pub use vm::{GuestVM, Perm};
 
 /// Number of IR operations to generate before interrupting the translation flow and starting a new
 /// translation block.
@@ -25,16 +29,18 @@ use std::{env, fs};
 pub const DEFAULT_TB_SIZE: usize = 4096;
 
 /// Read ELF into byte vector.  The ELF file is specified via commandline argument.
-pub fn read_elf() -> Result<Vec<u8>, String> {
+///
+/// The rest of the commandline, starting from the ELF name, is returned as the guest arguments.
+pub fn read_elf() -> Result<(Vec<u8>, Vec<String>), String> {
     let args: Vec<_> = env::args().collect();
     let prog_path;
     match args.len() {
-        2 => prog_path = Path::new(args[1].as_str()),
-        _ => return Err(format!("usage: {} <ELF name>", args[0])),
+        0 | 1 => return Err(format!("usage: {} <ELF name> [args...]", args[0])),
+        _ => prog_path = Path::new(args[1].as_str()),
     };
 
     match fs::read(prog_path) {
-        Ok(b) => Ok(b),
+        Ok(b) => Ok((b, args[1..].to_vec())),
         Err(e) => Err(format!("failed to read {}: {}", prog_path.display(), e)),
     }
 }
@@ -42,137 +48,182 @@ pub fn read_elf() -> Result<Vec<u8>, String> {
 /// Guest address space size.  Default to 512MB.
 pub const GUEST_SIZE: usize = 0x2000_0000;
 
-/// Type of guest virtual address space.
-pub type GuestMap = Rc<RefCell<MmapMut>>;
+/// Size of the guest stack at the top of the guest address space.  Default to 8MB.
+pub const STACK_SIZE: u64 = 0x80_0000;
 
-/// Map guest virtual memory.
-pub fn map_virtual() -> Result<GuestMap, String> {
-    MmapOptions::new()
-        .len(GUEST_SIZE)
-        .map_anon()
-        .map(|x| Rc::new(RefCell::new(x)))
-        .map_err(|e| format!("failed to map guest virtual space: {}", e))
+/// Page size of the guest.
+pub const PAGE_SIZE: u64 = 0x1000;
+
+/// Round `v` up to a multiple of the guest page size.
+pub fn page_align(v: u64) -> u64 {
+    (v + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
 }
 
-/// Routine to parse and load an ELF program.
-pub mod loader;
+/// Round `v` up to a multiple of the guest page size, or `None` if the result overflows.
+pub fn checked_page_align(v: u64) -> Option<u64> {
+    v.checked_add(PAGE_SIZE - 1).map(|v| v & !(PAGE_SIZE - 1))
+}
 
-/// Type of a guest trap handler.
+/// Environment variable to specify the guest sysroot.
 ///
-/// The guest trap handler accepts a trap cause `ir::op::TrapOp` and a per-trap-defined value.
-pub type TrapHandler = fn(u64, u64);
+/// The interpreter and shared libraries of dynamically linked programs are searched in the sysroot,
+/// and absolute paths accessed by the guest are looked up in the sysroot first.
+pub const SYSROOT_ENV: &str = "KHEMU_SYSROOT";
+
+/// Translate an absolute guest path into the guest sysroot, if the file exists there.
+pub fn guest_path(path: &Path) -> PathBuf {
+    if let (Some(sysroot), Ok(relative)) = (env::var_os(SYSROOT_ENV), path.strip_prefix("/")) {
+        let translated = Path::new(&sysroot).join(relative);
+        if translated.exists() {
+            return translated;
+        }
+    }
+    path.to_owned()
+}
 
-static mut START_POSITIONS: Option<VecDeque<usize>> = None;
+/// Type of guest virtual address space.
+pub type GuestMap = Rc<RefCell<GuestVM>>;
 
-fn trap_handler<C: HostContext + 'static>(cause: u64, val: u64) {
-    let trap_op = TrapOp::from_bits(cause).unwrap();
+/// Map guest virtual memory.
+pub fn map_virtual() -> Result<GuestMap, String> {
+    GuestVM::new().map(|x| Rc::new(RefCell::new(x)))
+}
 
-    C::get().handle_trap();
+/// Substitution of guest library functions with host functions.
+pub mod dynamic;
+/// Routine to parse and load an ELF program.
+pub mod loader;
+/// Semihosting support for the guest.
+pub mod semihosting;
+/// Linux system call proxy for the guest.
+pub mod syscall;
+/// Guest virtual memory management.
+pub mod vm;
+
+// drop the blocks translated from guest code that changed, such that they are translated again
+fn invalidate<C: HostContext + 'static>(
+    guest_map: &GuestMap,
+    blk_cache: &mut HashMap<usize, C::BlockType>,
+) {
+    let ranges = guest_map.borrow_mut().take_invalidated();
+    for range in ranges {
+        invalidate_range::<C>(range.start as usize..range.end as usize, blk_cache);
+    }
+}
 
-    match trap_op {
-        TrapOp::LOOKUP_TB => {
-            info!("Lookup TB: continuing at {:#x}", val);
-            // insert target right after pending
-            unsafe {
-                let waiting = START_POSITIONS.as_mut().unwrap().pop_front().unwrap();
-                START_POSITIONS.as_mut().unwrap().push_front(val as usize);
-                START_POSITIONS.as_mut().unwrap().push_front(waiting);
-            }
-        }
-        _ => unimplemented!(),
+// drop the blocks translated from guest code in `range`
+fn invalidate_range<C: HostContext + 'static>(
+    range: Range<usize>,
+    blk_cache: &mut HashMap<usize, C::BlockType>,
+) {
+    let blocks = C::get().unchain(range.clone());
+    if !blocks.is_empty() {
+        info!(
+            "Invalidated {} blocks in {:#x}..{:#x}",
+            blocks.len(),
+            range.start,
+            range.end
+        );
+    }
+    for pc in blocks {
+        blk_cache.remove(&pc);
     }
 }
 
 /// The main "disassemble-emit-execute" loop.
+///
+/// Emitted blocks run until they trap to the runtime.  The trap is handled here, and execution
+/// continues at the guest address reported by the block.
 pub fn do_work<C: HostContext + 'static>() -> Result<(), String> {
-    let elf = read_elf()?;
+    let (elf, args) = read_elf()?;
+
+    let (mut disassembler, info) = loader::load_program(elf, &args)?;
+    dynamic::init(Rc::clone(&info.guest_map), info.natives);
+    syscall::init(Rc::clone(&info.guest_map), info.brk, info.mmap_base);
+    let guest_map = info.guest_map;
+    // nothing has been translated from the code written by the loader yet
+    guest_map.borrow_mut().take_invalidated();
+
+    let mut cpu = Arm64CpuState {
+        sp: info.sp,
+        pc: info.entry,
+        exclusive_addr: !0,
+        ..Default::default()
+    };
+    let mut blk_cache: HashMap<usize, C::BlockType> = HashMap::new();
+    loop {
+        let pc = cpu.pc as usize;
+        // not found, translate and insert
+        if !blk_cache.contains_key(&pc) {
+            let name = format!("func_{}", pc);
+            C::get().push_block(&name, true);
+
+            let result = disassembler.disas_block(pc, DEFAULT_TB_SIZE);
+            let tb = disassembler.get_tb();
+            if let DisasException::Unexpected(s) = result {
+                error!("Ending TB @ {:#x} with error: {}", tb.start_pc, s);
+                return Err(s);
+            }
+            info!("Ending TB @ {:#x} with reason: {}", tb.start_pc, result);
 
-    let (mut disassembler, entry_point) = loader::load_program(elf, trap_handler::<C>)?;
-    let mut blk_cache: HashMap<_, C::BlockType> = HashMap::new();
+            // emit backend instructions
+            let blk = C::get().emit_block(tb, &name, disassembler.get_tracking(), Some(result));
+            blk_cache.insert(pc, blk);
+        }
 
-    unsafe {
-        START_POSITIONS = Some(VecDeque::new());
-        START_POSITIONS
-            .as_mut()
-            .unwrap()
-            .push_back(entry_point as usize);
-    }
+        // the previous block exited to this one, skip the runtime next time
+        C::get().chain(pc);
+        debug!("Executing host block for guest {:#x}", pc);
+        let mut exit = unsafe { blk_cache[&pc].execute(&mut cpu) };
 
-    let mut ret = None;
-    unsafe {
-        while let Some(&start_pos) = START_POSITIONS.as_mut().unwrap().front() {
-            match blk_cache.get(&start_pos) {
-                // found block, execute
-                Some(blk) => {
-                    info!("Executing host block for guest {:#x}", start_pos);
-                    unsafe {
-                        blk.execute();
-                    }
-                    START_POSITIONS.as_mut().unwrap().pop_front();
-                }
-                // not found, translate and insert
-                None => {
-                    let name = format!("func_{}", start_pos);
-                    C::get().push_block(&name, true);
-
-                    let result = disassembler.disas_block(start_pos, DEFAULT_TB_SIZE);
-                    let tb = disassembler.get_tb();
-                    match result {
-                        DisasException::Unexpected(s) => {
-                            error!("Ending TB @ {:#x} with error: {}", tb.start_pc, s);
-                            ret = Some(s);
-                            break;
-                        }
-                        e => {
-                            info!("Ending TB @ {:#x} with reason: {}", tb.start_pc, e);
-                            // find blocks that can be found statically
-                            match e {
-                                DisasException::Continue(dest) => {
-                                    // Size exceeded or unconditional jump
-                                    // insert target right after pending
-                                    let waiting =
-                                        START_POSITIONS.as_mut().unwrap().pop_front().unwrap();
-                                    START_POSITIONS.as_mut().unwrap().push_front(dest);
-                                    START_POSITIONS.as_mut().unwrap().push_front(waiting);
-                                }
-                                DisasException::Branch(Some(taken), Some(not_taken)) => {
-                                    // both destinations are known
-                                    // TODO(jsteward) modify to fit proper translation branch prediction
-                                    START_POSITIONS.as_mut().unwrap().push_back(taken);
-                                    START_POSITIONS.as_mut().unwrap().push_back(not_taken);
-                                }
-                                DisasException::Branch(Some(dest), None)
-                                | DisasException::Branch(None, Some(dest)) => {
-                                    // only one destination is known
-                                    // TODO(jsteward) modify to fit proper translation branch prediction
-                                    START_POSITIONS.as_mut().unwrap().push_back(dest);
-                                }
-                                _ => {
-                                    // none of the jump targets are known
-                                    // bail out, wait for actual LOOKUP trap
-                                }
-                            }
-
-                            // emit backend instructions
-                            let blk = C::get().emit_block(
-                                tb,
-                                &name,
-                                disassembler.get_tracking(),
-                                Some(e),
-                            );
-
-                            // record in cache, run it next round
-                            blk_cache.insert(start_pos, blk);
-                            continue;
-                        }
-                    }
-                }
+        match TrapOp::from_bits(exit.cause).expect("invalid trap cause") {
+            TrapOp::LOOKUP_TB => {
+                debug!("Lookup TB: continuing at {:#x}", exit.pc);
             }
+            TrapOp::SYSCALL => {
+                debug!("Syscall #{:#x} at {:#x}", exit.val, exit.pc);
+                syscall::do_syscall(&mut cpu);
+                // resume after the svc
+                exit.pc += 4;
+                // exception return clears the local exclusive monitor
+                cpu.exclusive_addr = !0;
+                // the syscall may have unmapped or changed guest code
+                invalidate::<C>(&guest_map, &mut blk_cache);
+            }
+            TrapOp::DYNAMIC => {
+                dynamic::do_dynamic(&mut cpu, exit.val);
+            }
+            TrapOp::SEMIHOSTING => {
+                semihosting::do_semihosting(&guest_map, &mut cpu);
+            }
+            TrapOp::INVALIDATE_CODE => {
+                // the dropped blocks are translated again from the new guest code when next
+                // reached; the backend emits them as new functions
+                let start = exit.val as usize;
+                invalidate_range::<C>(start..start + CACHE_LINE_SIZE as usize, &mut blk_cache);
+            }
+            TrapOp::BREAKPOINT => {
+                error!("Breakpoint #{:#x} at {:#x}", exit.val, exit.pc);
+                error!("Guest state: {:#x?}", cpu);
+                // terminate as if killed by SIGTRAP
+                process::exit(128 + libc::SIGTRAP);
+            }
+            TrapOp::ACCESS_FAULT => {
+                error!("Guest access fault at {:#x}", exit.val);
+                error!("Guest state: {:#x?}", cpu);
+                C::get().dump_code();
+                // terminate as if killed by SIGSEGV
+                process::exit(128 + libc::SIGSEGV);
+            }
+            TrapOp::UNDEF_OPCODE => {
+                error!("Undefined instruction at {:#x}", exit.val);
+                error!("Guest state: {:#x?}", cpu);
+                C::get().dump_code();
+                // terminate as if killed by SIGILL
+                process::exit(128 + libc::SIGILL);
+            }
+            _ => unreachable!(),
         }
-    }
-
-    match ret {
-        Some(r) => Err(r),
-        None => Ok(()),
+        cpu.pc = exit.pc;
     }
 }
@@ -0,0 +1,411 @@
+// SPDX-FileCopyrightText: 2020 Pengcheng Xu <i@jsteward.moe>
+//
+// SPDX-License-Identifier: BSD-3-Clause
+
+use crate::guest::arm64::Arm64CpuState;
+use crate::runtime::*;
+use log::*;
+
+use libc::c_void;
+use std::ffi::{CStr, CString};
+
+/// Environment variable to specify the file that lists the native functions to substitute.
+///
+/// Each line of the file has the form `<library> <function> <signature>`, e.g. `libm.so.6 pow
+/// dd:d`; empty lines and lines starting with `#` are ignored.  The signature lists the classes
+/// of the arguments and the return value, separated by `:`:
+/// - `x`: integer
+/// - `p`: pointer into guest memory, translated to and from the host
+/// - `d`: double precision floating point
+/// - `f`: single precision floating point
+/// - `v`: no return value
+///
+/// [DEFAULT_NATIVE](constant.DEFAULT_NATIVE.html) is used if the variable is not set.
+///
+/// The stubs are generated by the [builtin linker](../loader/link/index.html) only.  With the
+/// program interpreter (the default [`LINKER_ENV`](../loader/constant.LINKER_ENV.html)), no
+/// function is substituted and setting this variable is an error.  The workload in mind is a
+/// dynamically linked program spending its time in `libm.so.6`, e.g. one calling `pow` in a loop
+/// run with `KHEMU_LINKER=builtin`: the calls are resolved to the stubs of the default list.
+pub const NATIVE_ENV: &str = "KHEMU_NATIVE";
+
+/// Functions substituted when [NATIVE_ENV](constant.NATIVE_ENV.html) is not set.
+pub const DEFAULT_NATIVE: &str = "\
+libm.so.6 sin d:d
+libm.so.6 cos d:d
+libm.so.6 tan d:d
+libm.so.6 asin d:d
+libm.so.6 acos d:d
+libm.so.6 atan d:d
+libm.so.6 atan2 dd:d
+libm.so.6 sinh d:d
+libm.so.6 cosh d:d
+libm.so.6 tanh d:d
+libm.so.6 exp d:d
+libm.so.6 exp2 d:d
+libm.so.6 log d:d
+libm.so.6 log2 d:d
+libm.so.6 log10 d:d
+libm.so.6 pow dd:d
+libm.so.6 cbrt d:d
+libm.so.6 hypot dd:d
+libm.so.6 fmod dd:d
+";
+
+/// Immediate of the `HLT` instruction that marks a native function stub in guest code.
+///
+/// A stub is a `HLT` with this immediate followed by a `RET`.  The frontend translates the `HLT`
+/// into a `DYNAMIC` trap with the stub address.
+pub const STUB_HLT_IMM: u32 = 0x4b48;
+
+/// Size of a native function stub in guest code.
+pub const STUB_SIZE: u64 = 8;
+
+// AAPCS64 passes up to 8 arguments in each register class
+const MAX_ARGS: usize = 8;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Class {
+    Int,
+    Ptr,
+    Float,
+    Single,
+    Void,
+}
+
+impl Class {
+    fn parse(c: char, ret: bool) -> Option<Self> {
+        match c {
+            'x' => Some(Class::Int),
+            'p' => Some(Class::Ptr),
+            'd' => Some(Class::Float),
+            'f' => Some(Class::Single),
+            'v' if ret => Some(Class::Void),
+            _ => None,
+        }
+    }
+
+    // passed in the floating point registers
+    fn is_float(self) -> bool {
+        self == Class::Float || self == Class::Single
+    }
+}
+
+/// A host function that substitutes a guest library function.
+pub struct Native {
+    /// Guest library that defines the function.
+    pub lib: String,
+    /// Name of the function.
+    pub name: String,
+    args: Vec<Class>,
+    ret: Class,
+    func: *const c_void,
+}
+
+/// Generate the guest code of a native function stub.
+pub fn stub_code() -> [u8; STUB_SIZE as usize] {
+    let hlt = 0xd440_0000 | STUB_HLT_IMM << 5;
+    let ret = 0xd65f_03c0u32;
+    let mut code = [0; STUB_SIZE as usize];
+    code[..4].copy_from_slice(&hlt.to_le_bytes());
+    code[4..].copy_from_slice(&ret.to_le_bytes());
+    code
+}
+
+fn dlerror() -> String {
+    unsafe {
+        let e = libc::dlerror();
+        if e.is_null() {
+            "unknown error".to_owned()
+        } else {
+            CStr::from_ptr(e).to_string_lossy().into_owned()
+        }
+    }
+}
+
+// parse a line of the substitution list into (library, function, arguments, return)
+fn parse_line(line: &str) -> Result<(&str, &str, Vec<Class>, Class), String> {
+    let fields = line.split_whitespace().collect::<Vec<_>>();
+    let (lib, name, sig) = match fields.as_slice() {
+        &[lib, name, sig] => (lib, name, sig),
+        _ => return Err("expected <library> <function> <signature>".to_owned()),
+    };
+    let mut parts = sig.splitn(2, ':');
+    let (args, ret) = match (parts.next(), parts.next()) {
+        (Some(args), Some(ret)) if ret.len() == 1 => (args, ret.chars().next().unwrap()),
+        _ => return Err(format!("malformed signature {}", sig)),
+    };
+    let args = args
+        .chars()
+        .map(|c| Class::parse(c, false).ok_or(format!("unknown argument class {}", c)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let ret = Class::parse(ret, true).ok_or(format!("unknown return class {}", ret))?;
+    if args.iter().filter(|c| c.is_float()).count() > MAX_ARGS
+        || args.iter().filter(|c| !c.is_float()).count() > MAX_ARGS
+    {
+        return Err("arguments passed on the stack are not supported".to_owned());
+    }
+    Ok((lib, name, args, ret))
+}
+
+/// Read the substitution list and look up the functions on the host.
+///
+/// Functions that are not available on the host are skipped, such that the guest version is used.
+pub fn load_config() -> Result<Vec<Native>, String> {
+    let config = match env::var(NATIVE_ENV) {
+        Ok(path) => fs::read_to_string(&path)
+            .map_err(|e| format!("failed to read native function list {}: {}", path, e))?,
+        Err(_) => DEFAULT_NATIVE.to_owned(),
+    };
+
+    let mut handles = HashMap::new();
+    let mut ret = Vec::new();
+    for (i, line) in config.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (lib, name, args, class) =
+            parse_line(line).map_err(|e| format!("native function list line {}: {}", i + 1, e))?;
+
+        let handle = *handles.entry(lib.to_owned()).or_insert_with(|| {
+            let c_lib = CString::new(lib).unwrap();
+            let handle = unsafe { libc::dlopen(c_lib.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+            if handle.is_null() {
+                warn!("Host library {} not available: {}", lib, dlerror());
+            }
+            handle
+        });
+        if handle.is_null() {
+            continue;
+        }
+        let c_name = CString::new(name).unwrap();
+        let func = unsafe { libc::dlsym(handle, c_name.as_ptr()) };
+        if func.is_null() {
+            warn!("Host function {} not available: {}", name, dlerror());
+            continue;
+        }
+
+        ret.push(Native {
+            lib: lib.to_owned(),
+            name: name.to_owned(),
+            args,
+            ret: class,
+            func,
+        });
+    }
+    Ok(ret)
+}
+
+struct DynamicState {
+    guest_map: GuestMap,
+    stubs: HashMap<u64, Native>,
+}
+
+static mut DYNAMIC_STATE: Option<DynamicState> = None;
+
+/// Initialize the native function substitution with the stubs generated by the linker.
+pub fn init(guest_map: GuestMap, stubs: HashMap<u64, Native>) {
+    unsafe {
+        DYNAMIC_STATE = Some(DynamicState { guest_map, stubs });
+    }
+}
+
+// host functions are called with all the argument registers, as both the System V AMD64 ABI and
+// AAPCS64 allocate the integer and floating point registers independently, and the arguments
+// that do not fit in the host registers are placed on the stack in order
+type IntFunc = unsafe extern "C" fn(
+    u64,
+    u64,
+    u64,
+    u64,
+    u64,
+    u64,
+    u64,
+    u64,
+    f64,
+    f64,
+    f64,
+    f64,
+    f64,
+    f64,
+    f64,
+    f64,
+) -> u64;
+type FloatFunc = unsafe extern "C" fn(
+    u64,
+    u64,
+    u64,
+    u64,
+    u64,
+    u64,
+    u64,
+    u64,
+    f64,
+    f64,
+    f64,
+    f64,
+    f64,
+    f64,
+    f64,
+    f64,
+) -> f64;
+
+/// Call the host function for the stub at `addr`.
+///
+/// Per AAPCS64, the arguments are read from `x0`-`x7` and `d0`-`d7`, and the result is written
+/// back into `x0` or `d0`.  Pointers are translated between the guest and the host address space.
+pub fn do_dynamic(cpu: &mut Arm64CpuState, addr: u64) {
+    let state = unsafe {
+        DYNAMIC_STATE
+            .as_ref()
+            .expect("native function substitution not initialized")
+    };
+    let native = match state.stubs.get(&addr) {
+        Some(n) => n,
+        None => {
+            error!("No native function for stub at {:#x}", addr);
+            process::exit(1);
+        }
+    };
+    let base = state.guest_map.borrow().as_ptr() as u64;
+
+    let mut x = cpu.x[..MAX_ARGS].to_vec();
+    let d = cpu.d[..MAX_ARGS]
+        .iter()
+        .map(|&v| f64::from_bits(v))
+        .collect::<Vec<_>>();
+    // NULL stays NULL
+    let int_args = native.args.iter().filter(|c| !c.is_float());
+    for (reg, &class) in x.iter_mut().zip(int_args) {
+        if class == Class::Ptr && *reg != 0 {
+            if *reg >= GUEST_SIZE as u64 {
+                error!(
+                    "Native function {} called with pointer {:#x} outside of guest memory",
+                    native.name, *reg
+                );
+                process::exit(1);
+            }
+            *reg += base;
+        }
+    }
+
+    let ret = unsafe {
+        match native.ret {
+            Class::Float | Class::Single => {
+                let f: FloatFunc = std::mem::transmute(native.func);
+                f(
+                    x[0], x[1], x[2], x[3], x[4], x[5], x[6], x[7], d[0], d[1], d[2], d[3], d[4],
+                    d[5], d[6], d[7],
+                )
+                .to_bits()
+            }
+            _ => {
+                let f: IntFunc = std::mem::transmute(native.func);
+                f(
+                    x[0], x[1], x[2], x[3], x[4], x[5], x[6], x[7], d[0], d[1], d[2], d[3], d[4],
+                    d[5], d[6], d[7],
+                )
+            }
+        }
+    };
+    debug!(
+        "native {}({:#x?}, {:?}) = {:#x}",
+        native.name,
+        &x[..],
+        &d[..],
+        ret
+    );
+
+    match native.ret {
+        Class::Int => cpu.x[0] = ret,
+        Class::Ptr => {
+            let guest = if ret == 0 {
+                0
+            } else if ret >= base && ret < base + GUEST_SIZE as u64 {
+                ret - base
+            } else {
+                error!(
+                    "Native function {} returned host pointer {:#x} outside of guest memory",
+                    native.name, ret
+                );
+                0
+            };
+            cpu.x[0] = guest;
+        }
+        Class::Float => cpu.d[0] = ret,
+        // the upper half of xmm0 is undefined, writing s0 clears the upper half of d0
+        Class::Single => cpu.d[0] = ret & 0xffff_ffff,
+        Class::Void => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_signature() {
+        assert_eq!(
+            parse_line("libm.so.6 pow dd:d"),
+            Ok((
+                "libm.so.6",
+                "pow",
+                vec![Class::Float, Class::Float],
+                Class::Float
+            ))
+        );
+        assert_eq!(
+            parse_line("libc.so.6\tstrlen  p:x"),
+            Ok(("libc.so.6", "strlen", vec![Class::Ptr], Class::Int))
+        );
+        assert_eq!(
+            parse_line("libm.so.6 sincosf fpp:v"),
+            Ok((
+                "libm.so.6",
+                "sincosf",
+                vec![Class::Single, Class::Ptr, Class::Ptr],
+                Class::Void
+            ))
+        );
+        assert_eq!(
+            parse_line("libc.so.6 rand :x"),
+            Ok(("libc.so.6", "rand", vec![], Class::Int))
+        );
+    }
+
+    #[test]
+    fn parse_default_list() {
+        for line in DEFAULT_NATIVE.lines() {
+            assert!(parse_line(line).is_ok(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn reject_malformed() {
+        // wrong number of fields
+        assert!(parse_line("libm.so.6 pow").is_err());
+        assert!(parse_line("libm.so.6 pow dd:d extra").is_err());
+        // missing or multiple return classes
+        assert!(parse_line("libm.so.6 pow dd").is_err());
+        assert!(parse_line("libm.so.6 pow dd:").is_err());
+        assert!(parse_line("libm.so.6 pow dd:dd").is_err());
+        // unknown classes, and void arguments
+        assert!(parse_line("libm.so.6 pow dq:d").is_err());
+        assert!(parse_line("libm.so.6 pow dd:q").is_err());
+        assert!(parse_line("libc.so.6 abort v:v").is_err());
+    }
+
+    #[test]
+    fn reject_stack_arguments() {
+        assert!(parse_line("libx.so f xxxxxxxx:x").is_ok());
+        assert!(parse_line("libx.so f xxxxxxxxx:x").is_err());
+        assert!(parse_line("libx.so f ddddffff:x").is_ok());
+        assert!(parse_line("libx.so f ddddfffff:x").is_err());
+        // pointers are passed in integer registers
+        assert!(parse_line("libx.so f xxxxpppp:x").is_ok());
+        assert!(parse_line("libx.so f xxxxppppp:x").is_err());
+        // the register classes are allocated independently
+        assert!(parse_line("libx.so f xxxxxxxxdddddddd:x").is_ok());
+    }
+}
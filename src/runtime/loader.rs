@@ -5,78 +5,412 @@
 use crate::guest::arm64::Arm64GuestContext;
 use crate::guest::Disassembler;
 use crate::ir::storage::HostStorage;
+use crate::runtime::dynamic::Native;
+use crate::runtime::syscall::MMAP_BASE;
 use crate::runtime::*;
-use log::info;
+use log::{debug, info};
 
 use goblin::elf;
 use goblin::elf::header::*;
-use goblin::elf::program_header::pt_to_str;
+use goblin::elf::program_header::{pt_to_str, PT_LOAD, PT_PHDR};
 use std::rc::Rc;
 
 use std::cell::RefCell;
+use std::env;
+use std::fs::{self, File};
+use std::io::Read;
 use std::ops::IndexMut;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 
-/// Loads a guest ELF and creates the frontend context, also known as the disassembler.
-pub fn load_program<R: HostStorage>(
+/// Builtin dynamic linker that loads the dependent libraries of a program.
+pub mod link;
+
+/// Hardware capabilities reported to the guest in `AT_HWCAP`.
+///
+/// Only features that the frontend can translate should be reported.
+pub const HWCAP: u64 = HWCAP_ATOMICS | HWCAP_CPUID;
+
+const HWCAP_ATOMICS: u64 = 1 << 8;
+const HWCAP_CPUID: u64 = 1 << 11;
+
+/// Environment variable to select how dynamically linked programs are loaded.
+///
+/// - `interp` (default): load the program interpreter specified in `PT_INTERP`, which in turn
+///   loads the dependent libraries from the [sysroot](../constant.SYSROOT_ENV.html)
+/// - `builtin`: load the `DT_NEEDED` libraries and apply relocations in the
+///   [builtin linker](link/index.html)
+pub const LINKER_ENV: &str = "KHEMU_LINKER";
+
+/// Guest address to load position-independent executables at.
+pub const PIE_BASE: u64 = 0x40_0000;
+
+/// Information about a loaded guest program required to start its execution.
+pub struct LoadInfo {
+    /// The guest address space the program is loaded into.
+    pub guest_map: GuestMap,
+    /// Entry point of the program.
+    pub entry: u64,
+    /// Initial stack pointer.
+    pub sp: u64,
+    /// Initial program break, i.e. the page-aligned end of the highest loaded segment.
+    pub brk: u64,
+    /// Guest address to start placing anonymous mappings at, after all loaded shared objects.
+    pub mmap_base: u64,
+    /// Native functions substituted by the builtin linker, by guest stub address.
+    pub natives: HashMap<u64, Native>,
+}
+
+/// An ELF object loaded into the guest address space.
+pub struct Object {
+    /// Name of the object: the path for the program and interpreter, the `DT_NEEDED` entry
+    /// for libraries.
+    pub name: String,
+    /// Load bias, i.e. the difference between the guest addresses and the ELF virtual addresses.
+    pub bias: u64,
+    /// Page-aligned guest address after the highest segment.
+    pub end: u64,
+    /// Entry point in guest address.
+    pub entry: u64,
+    /// Guest address of the program headers.
+    pub phdr: Option<u64>,
+    /// The program interpreter in `PT_INTERP`.
+    pub interp: Option<String>,
+    // contents of the ELF file, kept for the linker
+    buffer: Vec<u8>,
+}
+
+impl Object {
+    /// Parse the ELF file of the object.
+    pub fn elf(&self) -> elf::Elf<'_> {
+        // already parsed successfully when loading
+        elf::Elf::parse(&self.buffer).unwrap()
+    }
+}
+
+/// Load the `PT_LOAD` segments of an ELF object into the guest address space.
+///
+/// Position-independent objects (`ET_DYN`) are placed at `base`; others are placed at their
+/// virtual addresses.
+pub fn load_object(
+    guest_map: &GuestMap,
+    name: &str,
     buffer: Vec<u8>,
-    handler: TrapHandler,
-) -> Result<(impl Disassembler<R>, u64), String> {
-    let binary: elf::Elf = match elf::Elf::parse(&buffer) {
-        Ok(b) => b,
-        Err(e) => return Err(format!("failed to parse ELF: {}", e)),
+    base: u64,
+) -> Result<Object, String> {
+    let binary =
+        elf::Elf::parse(&buffer).map_err(|e| format!("failed to parse ELF {}: {}", name, e))?;
+
+    if binary.header.e_machine != EM_AARCH64 {
+        return Err(format!(
+            "{}: unsupported architecture {}",
+            name,
+            elf::header::machine_to_str(binary.header.e_machine)
+        ));
+    }
+
+    let segments = binary
+        .program_headers
+        .iter()
+        .filter(|ph| ph.p_type == PT_LOAD)
+        .collect::<Vec<_>>();
+    let bias = match binary.header.e_type {
+        ET_EXEC => 0,
+        ET_DYN => {
+            // objects linked at a nonzero address may be moved down, the bias wraps around
+            let start = segments.iter().map(|ph| ph.p_vaddr).min().unwrap_or(0);
+            base.wrapping_sub(start & !(PAGE_SIZE - 1))
+        }
+        t => {
+            return Err(format!(
+                "{}: requested to load executable (EXEC or DYN) but ELF type is {}",
+                name,
+                et_to_str(t)
+            ))
+        }
     };
 
-    match binary.header.e_machine {
-        EM_AARCH64 => {
-            if binary.header.e_type != ET_EXEC && binary.header.e_type != ET_DYN {
+    let mut end = 0;
+    let mut phdr = None;
+    let phoff = binary.header.e_phoff;
+    for ph in segments {
+        let virt = ph.p_vaddr.wrapping_add(bias);
+        let file_off = ph.p_offset as usize;
+        let len = ph.p_filesz;
+        let mem_len = ph.p_memsz;
+        let perm = Perm::from_elf(ph.p_flags);
+
+        match virt.checked_add(mem_len) {
+            Some(seg_end) if len <= mem_len && seg_end <= GUEST_SIZE as u64 - STACK_SIZE => {
+                end = end.max(seg_end)
+            }
+            _ => {
                 return Err(format!(
-                    "requested to load executable (EXEC or DYN) but ELF type is {}",
-                    et_to_str(binary.header.e_type)
-                ));
+                    "{}: bad segment of {:#x} bytes at {:#x}",
+                    name, mem_len, virt
+                ))
             }
-
-            if let Some(_) = binary.dynamic {
-                return Err("dynamically linked executable not supported yet".to_owned());
+        }
+        match ph.p_offset.checked_add(len) {
+            Some(file_end) if file_end <= buffer.len() as u64 => {}
+            _ => {
+                return Err(format!(
+                    "{}: segment at {:#x} extends beyond the end of the file",
+                    name, virt
+                ))
             }
+        }
+        // the program headers are usually loaded as part of the first segment
+        if phoff >= ph.p_offset && phoff - ph.p_offset < len {
+            phdr = Some(virt + phoff - ph.p_offset);
+        }
+
+        info!(
+            "{}: mapping {:#x} bytes at {:#x} with {:?}, reading {:#x} bytes",
+            pt_to_str(ph.p_type),
+            mem_len,
+            virt,
+            perm,
+            len
+        );
+
+        // memsz may be larger than filesz, in which case the rest (.bss) is zero-filled
+        let data = &buffer[file_off..file_off + len as usize];
+        let mut vm = guest_map.borrow_mut();
+        vm.map(virt, mem_len, perm);
+        vm.write(virt, data);
+        vm.zero(virt + len, mem_len - len);
+    }
+
+    // prefer the explicit location if present
+    if let Some(ph) = binary
+        .program_headers
+        .iter()
+        .find(|ph| ph.p_type == PT_PHDR)
+    {
+        phdr = Some(ph.p_vaddr.wrapping_add(bias));
+    }
+
+    info!("Loaded {} with bias {:#x}", name, bias);
+
+    Ok(Object {
+        name: name.to_owned(),
+        bias,
+        end: page_align(end),
+        entry: binary.entry.wrapping_add(bias),
+        phdr,
+        interp: binary.interpreter.map(str::to_owned),
+        buffer,
+    })
+}
+
+// push `data` onto the guest stack at `sp`, returning the new stack pointer
+fn push_bytes(map: &GuestMap, sp: u64, data: &[u8]) -> u64 {
+    let sp = sp - data.len() as u64;
+    map.borrow_mut()
+        .index_mut(sp as usize..sp as usize + data.len())
+        .copy_from_slice(data);
+    sp
+}
+
+/// Set up the initial guest stack per the Linux aarch64 ABI.
+///
+/// From the top of the stack downwards, the following are placed:
+/// - the strings for `argv` and `envp`, and the 16 random bytes for `AT_RANDOM`
+/// - the auxiliary vector, terminated by `AT_NULL`
+/// - the `envp` pointers, terminated by `NULL`
+/// - the `argv` pointers, terminated by `NULL`
+/// - `argc`, pointed to by the returned initial stack pointer
+///
+/// `AT_RANDOM` should not be included in `auxv` as it is generated here.
+fn setup_stack(
+    map: &GuestMap,
+    args: &[Vec<u8>],
+    envs: &[Vec<u8>],
+    auxv: &[(u64, u64)],
+) -> Result<u64, String> {
+    // check the size before writing anything, as the memory below the stack is not accessible.
+    // Strings are NUL-terminated; the words are argc, argv, envp and auxv with AT_RANDOM and
+    // AT_NULL, and up to 15 bytes are lost when aligning the stack pointer
+    let strings = args
+        .iter()
+        .chain(envs)
+        .map(|s| s.len() as u64 + 1)
+        .sum::<u64>();
+    let num_words = 3 + args.len() + envs.len() + (auxv.len() + 2) * 2;
+    if strings + 16 + num_words as u64 * 8 + 15 > STACK_SIZE {
+        return Err("arguments and environment do not fit in the guest stack".to_owned());
+    }
+
+    let mut sp = GUEST_SIZE as u64;
+
+    let mut push_strings = |strings: &[Vec<u8>]| {
+        strings
+            .iter()
+            .map(|s| {
+                sp = push_bytes(map, sp, &[0]);
+                sp = push_bytes(map, sp, s);
+                sp
+            })
+            .collect::<Vec<_>>()
+    };
+    let argv = push_strings(args);
+    let envp = push_strings(envs);
+
+    let mut random = [0u8; 16];
+    File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut random))
+        .map_err(|e| format!("failed to generate AT_RANDOM bytes: {}", e))?;
+    sp = push_bytes(map, sp, &random);
+    let random = sp;
+
+    let mut words = vec![argv.len() as u64];
+    words.extend(argv);
+    words.push(0);
+    words.extend(envp);
+    words.push(0);
+    for &(k, v) in auxv
+        .iter()
+        .chain(&[(libc::AT_RANDOM, random), (libc::AT_NULL, 0)])
+    {
+        words.push(k);
+        words.push(v);
+    }
+
+    // the stack pointer must be 16-byte aligned at process entry
+    sp = (sp - words.len() as u64 * 8) & !0xf;
+    let data = words
+        .iter()
+        .flat_map(|w| w.to_le_bytes().to_vec())
+        .collect::<Vec<_>>();
+    push_bytes(map, sp + data.len() as u64, &data);
+
+    Ok(sp)
+}
+
+/// Loads a guest ELF and creates the frontend context, also known as the disassembler.
+///
+/// The guest stack is set up with `args` as the arguments and the host environment as the
+/// environment of the guest program.  Dynamically linked programs are loaded as specified by
+/// [`LINKER_ENV`](constant.LINKER_ENV.html).
+pub fn load_program<R: HostStorage>(
+    buffer: Vec<u8>,
+    args: &[String],
+) -> Result<(impl Disassembler<R>, LoadInfo), String> {
+    // mmap for guest virtual
+    let guest_map = map_virtual()?;
+    info!(
+        "Created guest address space at {:#x}",
+        guest_map.borrow().as_ptr() as usize
+    );
+
+    let main = load_object(&guest_map, &args[0], buffer, PIE_BASE)?;
+    let brk = main.end;
+    if brk > MMAP_BASE {
+        return Err(format!("program break {:#x} overlaps with mmap area", brk));
+    }
+    info!("Entry point: {:#x}, program break: {:#x}", main.entry, brk);
 
-            // mmap for guest virtual
-            let guest_map = map_virtual()?;
-            info!(
-                "Created guest address space at {:#x}",
-                guest_map.as_ptr() as usize
-            );
-
-            for ph in &binary.program_headers {
-                if ph.p_type == elf::program_header::PT_LOAD {
-                    let len = ph.p_filesz as usize;
-                    let file_off = ph.p_offset as usize;
-                    let virt = ph.p_vaddr as usize;
-
-                    info!(
-                        "{}: reading {:#x} bytes for {:#x}",
-                        pt_to_str(ph.p_type),
-                        len,
-                        virt
-                    );
-
-                    // memsz may be larger than filesz, in which case the rest is zero-filled
-                    let data = &buffer[file_off..file_off + len];
-                    guest_map
-                        .borrow_mut()
-                        .index_mut(virt..virt + len)
-                        .copy_from_slice(data);
+    let mut entry = main.entry;
+    let mut interp_base = 0;
+    let mut mmap_base = MMAP_BASE;
+    let mut startup = None;
+    let mut natives = HashMap::new();
+    if main.elf().dynamic.is_some() {
+        match env::var(LINKER_ENV).as_ref().map(String::as_str) {
+            Err(_) | Ok("interp") => {
+                // the interpreter resolves the symbols in the guest, where no stubs exist
+                if env::var_os(dynamic::NATIVE_ENV).is_some() {
+                    return Err(format!(
+                        "native function substitution requires {}=builtin",
+                        LINKER_ENV
+                    ));
+                }
+                // static PIE programs do not have an interpreter and relocate themselves
+                if let Some(interp) = &main.interp {
+                    let path = guest_path(Path::new(interp));
+                    let buffer = fs::read(&path).map_err(|e| {
+                        format!("failed to read interpreter {}: {}", path.display(), e)
+                    })?;
+                    let interp = load_object(&guest_map, interp, buffer, mmap_base)?;
+                    interp_base = interp.bias;
+                    entry = interp.entry;
+                    mmap_base = interp.end;
                 }
             }
+            Ok("builtin") => {
+                let (s, n, end) = link::link(&guest_map, &main, mmap_base)?;
+                startup = Some(s);
+                natives = n;
+                mmap_base = end;
+            }
+            Ok(l) => return Err(format!("unknown linker {}", l)),
+        }
+    }
 
-            info!("Entry point: {:#x}", binary.entry);
+    let stack_base = GUEST_SIZE as u64 - STACK_SIZE;
+    guest_map
+        .borrow_mut()
+        .map(stack_base, STACK_SIZE, Perm::READ | Perm::WRITE);
 
-            R::HostContext::init(Rc::clone(&guest_map), handler);
+    let binary = main.elf();
+    let args = args
+        .iter()
+        .map(|a| a.as_bytes().to_vec())
+        .collect::<Vec<_>>();
+    let envs = env::vars_os()
+        .map(|(k, v)| [k.as_bytes(), b"=", v.as_bytes()].concat())
+        .collect::<Vec<_>>();
+    let auxv = [
+        (
+            libc::AT_PHDR,
+            main.phdr.ok_or("program headers not loaded")?,
+        ),
+        (libc::AT_PHENT, binary.header.e_phentsize as u64),
+        (libc::AT_PHNUM, binary.header.e_phnum as u64),
+        (libc::AT_PAGESZ, PAGE_SIZE),
+        (libc::AT_BASE, interp_base),
+        (libc::AT_ENTRY, main.entry),
+        (libc::AT_UID, unsafe { libc::getuid() } as u64),
+        (libc::AT_EUID, unsafe { libc::geteuid() } as u64),
+        (libc::AT_GID, unsafe { libc::getgid() } as u64),
+        (libc::AT_EGID, unsafe { libc::getegid() } as u64),
+        (libc::AT_SECURE, 0),
+        (libc::AT_HWCAP, HWCAP),
+    ];
+    let sp = setup_stack(&guest_map, &args, &envs, &auxv)?;
+    info!("Initial stack pointer: {:#x}", sp);
 
-            Ok((Arm64GuestContext::<R>::new(guest_map), binary.entry))
-        }
-        _ => Err(format!(
-            "unsupported architecture {}",
-            elf::header::machine_to_str(binary.header.e_machine)
-        )),
+    if let Some(startup) = startup {
+        // run library initializers before the program
+        let argc = args.len() as u64;
+        let (start, end) = startup.emit(
+            &guest_map,
+            mmap_base,
+            argc,
+            sp + 8,
+            sp + 8 * (argc + 2),
+            entry,
+        );
+        entry = start;
+        mmap_base = end;
     }
+    info!("Starting at {:#x}", entry);
+
+    R::HostContext::init(Rc::clone(&guest_map));
+
+    // fixed registers are created with the disassembler
+    let disassembler = Arm64GuestContext::<R>::new(Rc::clone(&guest_map));
+
+    Ok((
+        disassembler,
+        LoadInfo {
+            guest_map,
+            entry,
+            sp,
+            brk,
+            mmap_base,
+            natives,
+        },
+    ))
 }
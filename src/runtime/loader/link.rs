@@ -0,0 +1,553 @@
+// SPDX-FileCopyrightText: 2020 Pengcheng Xu <i@jsteward.moe>
+//
+// SPDX-License-Identifier: BSD-3-Clause
+
+use super::*;
+use crate::runtime::dynamic::{self, Native};
+use log::warn;
+
+use goblin::elf::program_header::PT_TLS;
+use goblin::elf::reloc::*;
+use goblin::elf::section_header::SHN_UNDEF;
+use goblin::elf::sym::{Sym, STB_GLOBAL, STB_LOCAL, STB_WEAK, STT_GNU_IFUNC};
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryInto;
+
+/// Directories in the guest sysroot to search for libraries.
+pub const LIB_PATHS: [&str; 6] = [
+    "/lib/aarch64-linux-gnu",
+    "/usr/lib/aarch64-linux-gnu",
+    "/lib64",
+    "/usr/lib64",
+    "/lib",
+    "/usr/lib",
+];
+
+/// Guest code that has to run before the program entry.
+///
+/// The builtin linker cannot run guest code by itself.  Instead, a startup stub is generated
+/// into the guest address space to set the thread pointer, call the `IRELATIVE` resolvers and
+/// the library initializers before jumping to the program entry.
+pub struct Startup {
+    // thread pointer for `tpidr_el0`
+    tp: u64,
+    // (GOT entry, resolver)
+    irelative: Vec<(u64, u64)>,
+    init: Vec<u64>,
+}
+
+// size of the thread control block at the thread pointer, which holds the DTV pointer
+const TCB_SIZE: u64 = 16;
+// space below the thread pointer for the thread descriptor of the C library
+const PRE_TCB_SIZE: u64 = PAGE_SIZE;
+
+// resolver of the TLS descriptors for the static TLS blocks, which returns the offset from the
+// thread pointer stored in the descriptor: `ldr x0, [x0, #8]; ret`
+const TLSDESC_RESOLVER: [u32; 2] = [0xf940_0400, 0xd65f_03c0];
+const TLSDESC_RESOLVER_SIZE: u64 = 8;
+
+fn align_up(v: u64, align: u64) -> u64 {
+    (v + align - 1) / align * align
+}
+
+// offsets from the thread pointer of the TLS blocks with the given sizes and alignments, laid out
+// after the TCB as in AArch64 TLS variant I, and the offset after the last block
+fn tls_layout(blocks: &[(u64, u64)]) -> (Vec<u64>, u64) {
+    let mut end = TCB_SIZE;
+    let offsets = blocks
+        .iter()
+        .map(|&(size, align)| {
+            let offset = align_up(end, align.max(1));
+            end = offset + size;
+            offset
+        })
+        .collect();
+    (offsets, end)
+}
+
+// AArch64 encodings for the startup stub
+fn emit_mov(code: &mut Vec<u32>, rd: u32, imm: u64) {
+    // movz, then movk for the other halfwords
+    code.push(0xd280_0000 | ((imm & 0xffff) as u32) << 5 | rd);
+    for hw in 1..4 {
+        let imm16 = ((imm >> (16 * hw)) & 0xffff) as u32;
+        code.push(0xf280_0000 | hw << 21 | imm16 << 5 | rd);
+    }
+}
+
+fn emit_blr(code: &mut Vec<u32>, rn: u32) {
+    code.push(0xd63f_0000 | rn << 5);
+}
+
+fn emit_br(code: &mut Vec<u32>, rn: u32) {
+    code.push(0xd61f_0000 | rn << 5);
+}
+
+// str xt, [xn]
+fn emit_str(code: &mut Vec<u32>, rt: u32, rn: u32) {
+    code.push(0xf900_0000 | rn << 5 | rt);
+}
+
+// msr tpidr_el0, xt
+fn emit_msr_tpidr(code: &mut Vec<u32>, rt: u32) {
+    code.push(0xd51b_d040 | rt);
+}
+
+impl Startup {
+    /// Generate the startup stub at `addr` that ends by jumping to `entry`.
+    ///
+    /// The library initializers are called with `argc`, `argv` and `envp`.  Returns the address
+    /// of the stub and the page-aligned guest address after it.
+    pub fn emit(
+        &self,
+        guest_map: &GuestMap,
+        addr: u64,
+        argc: u64,
+        argv: u64,
+        envp: u64,
+        entry: u64,
+    ) -> (u64, u64) {
+        let mut code = Vec::new();
+        emit_mov(&mut code, 16, self.tp);
+        emit_msr_tpidr(&mut code, 16);
+        for &(slot, resolver) in &self.irelative {
+            emit_mov(&mut code, 0, HWCAP);
+            emit_mov(&mut code, 16, resolver);
+            emit_blr(&mut code, 16);
+            emit_mov(&mut code, 17, slot);
+            emit_str(&mut code, 0, 17);
+        }
+        for &init in &self.init {
+            emit_mov(&mut code, 0, argc);
+            emit_mov(&mut code, 1, argv);
+            emit_mov(&mut code, 2, envp);
+            emit_mov(&mut code, 16, init);
+            emit_blr(&mut code, 16);
+        }
+        // no finalizer to register with atexit
+        emit_mov(&mut code, 0, 0);
+        emit_mov(&mut code, 16, entry);
+        emit_br(&mut code, 16);
+
+        let code = code
+            .iter()
+            .flat_map(|i| i.to_le_bytes().to_vec())
+            .collect::<Vec<_>>();
+        let len = page_align(code.len() as u64);
+        let mut vm = guest_map.borrow_mut();
+        vm.map(addr, len, Perm::READ | Perm::EXEC);
+        vm.write(addr, &code);
+
+        info!(
+            "Startup stub at {:#x}: {} IRELATIVE resolvers, {} initializers",
+            addr,
+            self.irelative.len(),
+            self.init.len()
+        );
+        (addr, addr + len)
+    }
+}
+
+// find a library in the guest sysroot
+fn find_library(name: &str) -> Result<PathBuf, String> {
+    if name.contains('/') {
+        return Ok(guest_path(Path::new(name)));
+    }
+    LIB_PATHS
+        .iter()
+        .map(|dir| guest_path(&Path::new(dir).join(name)))
+        .find(|p| p.exists())
+        .ok_or(format!("library {} not found", name))
+}
+
+// defined symbols exported from an object
+fn exports<'a>(elf: &elf::Elf<'a>) -> HashMap<&'a str, Sym> {
+    elf.dynsyms
+        .iter()
+        .filter(|s| s.st_shndx != SHN_UNDEF as usize)
+        .filter(|s| s.st_bind() == STB_GLOBAL || s.st_bind() == STB_WEAK)
+        .filter_map(|s| match elf.dynstrtab.get(s.st_name) {
+            Some(Ok(name)) => Some((name, s)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Load the `DT_NEEDED` libraries of `main` recursively and relocate all the objects.
+///
+/// The libraries are placed from `base` on.  Symbols are looked up in the order of loading,
+/// starting from the program itself; symbol versions are not considered.  References to the
+/// [native functions](../../dynamic/index.html) defined in their libraries are resolved to stubs
+/// placed after the libraries.  Thread-local storage is supported for the initial thread only:
+/// the TLS blocks of all the objects are allocated statically after the stubs.  Returns the
+/// startup code to run before the program entry, the native functions by stub address, and the
+/// guest address after the TLS blocks.
+///
+/// Fails on relocation types that are not supported, and on relocations or initializers outside
+/// of the loaded segments.
+pub fn link(
+    guest_map: &GuestMap,
+    main: &Object,
+    base: u64,
+) -> Result<(Startup, HashMap<u64, Native>, u64), String> {
+    let mut libs: Vec<Object> = Vec::new();
+    let mut base = base;
+
+    // breadth-first search for dependencies
+    let mut needed = main
+        .elf()
+        .libraries
+        .iter()
+        .map(|&l| l.to_owned())
+        .collect::<VecDeque<_>>();
+    while let Some(name) = needed.pop_front() {
+        if libs.iter().any(|l| l.name == name) {
+            continue;
+        }
+        let path = find_library(&name)?;
+        let buffer = fs::read(&path)
+            .map_err(|e| format!("failed to read library {}: {}", path.display(), e))?;
+        let lib = load_object(guest_map, &name, buffer, base)?;
+        needed.extend(lib.elf().libraries.iter().map(|&l| l.to_owned()));
+        base = lib.end;
+        libs.push(lib);
+    }
+
+    let objects = std::iter::once(main).chain(&libs).collect::<Vec<_>>();
+    let elfs = objects.iter().map(|o| o.elf()).collect::<Vec<_>>();
+    let scope = elfs.iter().map(exports).collect::<Vec<_>>();
+
+    // resolve a symbol in the global scope, skipping the first `skip` objects
+    let lookup = |name: &str, skip: usize| {
+        objects
+            .iter()
+            .zip(&scope)
+            .skip(skip)
+            .find_map(|(o, s)| s.get(name).map(|sym| (o, sym)))
+    };
+
+    // TLS segments of the objects.  The module IDs count from 1 in the order of loading, such
+    // that the block of the program comes first as assumed by its local-exec accesses
+    let mut tls_segments = Vec::new();
+    for (obj, elf) in objects.iter().zip(&elfs) {
+        let ph = elf.program_headers.iter().find(|ph| ph.p_type == PT_TLS);
+        if let Some(ph) = ph {
+            let image = obj.bias.wrapping_add(ph.p_vaddr);
+            if ph.p_align > PAGE_SIZE
+                || ph.p_filesz > ph.p_memsz
+                || ph.p_memsz > GUEST_SIZE as u64
+                || !guest_map.borrow().all_mapped(image, ph.p_filesz)
+            {
+                return Err(format!("{}: bad TLS segment at {:#x}", obj.name, image));
+            }
+        }
+        tls_segments.push(ph.cloned());
+    }
+    let blocks = tls_segments
+        .iter()
+        .flatten()
+        .map(|ph| (ph.p_memsz, ph.p_align))
+        .collect::<Vec<_>>();
+    let (offsets, tls_size) = tls_layout(&blocks);
+    let tls_align = blocks.iter().map(|&(_, a)| a).fold(TCB_SIZE, u64::max);
+    let mut offsets = offsets.into_iter();
+    let mut modules = 0;
+    // (module ID, offset from the thread pointer) of the TLS block of each object
+    let tls = tls_segments
+        .iter()
+        .map(|ph| {
+            ph.as_ref().map(|_| {
+                modules += 1;
+                (modules, offsets.next().unwrap())
+            })
+        })
+        .collect::<Vec<_>>();
+
+    // resolve a TLS symbol referenced by object `i` to the defining object and the offset in its
+    // TLS block
+    let lookup_tls = |i: usize, r_sym: usize, sym: &Sym, name: &str| {
+        if r_sym == 0 {
+            Some((i, 0))
+        } else if sym.st_bind() == STB_LOCAL {
+            Some((i, sym.st_value))
+        } else {
+            scope
+                .iter()
+                .enumerate()
+                .find_map(|(j, s)| s.get(name).map(|def| (j, def.st_value)))
+        }
+    };
+
+    let mut natives = dynamic::load_config()?
+        .into_iter()
+        .map(|n| (n.name.clone(), n))
+        .collect::<HashMap<_, _>>();
+    // the TLS descriptor resolver comes first, followed by the native function stubs
+    let stub_base = base;
+    let tlsdesc = stub_base;
+    let mut stubs = HashMap::new();
+
+    let mut startup = Startup {
+        tp: 0,
+        irelative: Vec::new(),
+        init: Vec::new(),
+    };
+    let mut copies = Vec::new();
+    let mut unsupported = BTreeSet::new();
+    let mut vm = guest_map.borrow_mut();
+    let mut write = |addr: u64, val: u64| {
+        if vm.all_mapped(addr, 8) {
+            vm.write(addr, &val.to_le_bytes());
+            Ok(())
+        } else {
+            Err(format!("relocation at unmapped address {:#x}", addr))
+        }
+    };
+
+    for (i, (obj, elf)) in objects.iter().zip(&elfs).enumerate() {
+        let relocs = elf.dynrelas.iter().chain(elf.pltrelocs.iter());
+        for reloc in relocs {
+            let target = obj.bias.wrapping_add(reloc.r_offset);
+            let addend = reloc.r_addend.unwrap_or(0) as u64;
+            let sym = elf.dynsyms.get(reloc.r_sym).unwrap();
+            let name = match elf.dynstrtab.get(sym.st_name) {
+                Some(Ok(name)) => name,
+                _ => "",
+            };
+
+            let result = match reloc.r_type {
+                R_AARCH64_NONE => Ok(()),
+                R_AARCH64_RELATIVE => write(target, obj.bias.wrapping_add(addend)),
+                R_AARCH64_IRELATIVE => {
+                    startup
+                        .irelative
+                        .push((target, obj.bias.wrapping_add(addend)));
+                    Ok(())
+                }
+                R_AARCH64_ABS64 | R_AARCH64_GLOB_DAT | R_AARCH64_JUMP_SLOT => {
+                    // the relocation may refer to a local symbol of the object
+                    let resolved = if reloc.r_sym == 0 {
+                        Some(0)
+                    } else if sym.st_bind() == STB_LOCAL {
+                        Some(obj.bias.wrapping_add(sym.st_value))
+                    } else {
+                        match lookup(name, 0) {
+                            // substituted by a host function
+                            Some((o, _)) if natives.get(name).map(|n| &n.lib) == Some(&o.name) => {
+                                let next = stub_base
+                                    + TLSDESC_RESOLVER_SIZE
+                                    + stubs.len() as u64 * dynamic::STUB_SIZE;
+                                Some(*stubs.entry(name).or_insert(next))
+                            }
+                            Some((o, def)) if def.st_type() == STT_GNU_IFUNC => {
+                                startup
+                                    .irelative
+                                    .push((target, o.bias.wrapping_add(def.st_value)));
+                                None
+                            }
+                            Some((o, def)) => Some(o.bias.wrapping_add(def.st_value)),
+                            None if sym.st_bind() == STB_WEAK => Some(0),
+                            None => {
+                                warn!("{}: undefined symbol {}", obj.name, name);
+                                None
+                            }
+                        }
+                    };
+                    match resolved {
+                        Some(v) => write(target, v.wrapping_add(addend)),
+                        None => Ok(()),
+                    }
+                }
+                // the source data may have relocations in the library
+                R_AARCH64_COPY => {
+                    copies.push((target, name, sym.st_size));
+                    Ok(())
+                }
+                R_AARCH64_TLS_DTPMOD | R_AARCH64_TLS_DTPREL | R_AARCH64_TLS_TPREL
+                | R_AARCH64_TLSDESC => match lookup_tls(i, reloc.r_sym, &sym, name) {
+                    Some((j, value)) => {
+                        let (module, offset) = tls[j].ok_or(format!(
+                            "TLS symbol {} in {} without TLS segment",
+                            name, objects[j].name
+                        ))?;
+                        let value = value.wrapping_add(addend);
+                        match reloc.r_type {
+                            R_AARCH64_TLS_DTPMOD => write(target, module),
+                            R_AARCH64_TLS_DTPREL => write(target, value),
+                            R_AARCH64_TLS_TPREL => write(target, offset.wrapping_add(value)),
+                            _ => write(target, tlsdesc)
+                                .and_then(|_| write(target + 8, offset.wrapping_add(value))),
+                        }
+                    }
+                    None => {
+                        warn!("{}: undefined TLS symbol {}", obj.name, name);
+                        Ok(())
+                    }
+                },
+                t => {
+                    unsupported.insert(r_to_str(t, EM_AARCH64));
+                    Ok(())
+                }
+            };
+            result.map_err(|e| format!("{}: {}", obj.name, e))?;
+        }
+        if !unsupported.is_empty() {
+            return Err(format!(
+                "{}: unsupported relocations {}",
+                obj.name,
+                unsupported.into_iter().collect::<Vec<_>>().join(", ")
+            ));
+        }
+    }
+
+    for (target, name, size) in copies {
+        // copy relocations are only valid in the program, the definition lives in a library
+        match lookup(name, 1) {
+            Some((o, def)) => {
+                let src = o.bias.wrapping_add(def.st_value);
+                if !vm.all_mapped(src, size) || !vm.all_mapped(target, size) {
+                    return Err(format!("{}: bad copy relocation for {}", main.name, name));
+                }
+                let src = src as usize;
+                let data = vm[src..src + size as usize].to_vec();
+                vm.write(target, &data);
+            }
+            None => warn!("{}: undefined symbol {} for copy", main.name, name),
+        }
+    }
+
+    let stub_end =
+        page_align(stub_base + TLSDESC_RESOLVER_SIZE + stubs.len() as u64 * dynamic::STUB_SIZE);
+    vm.map(stub_base, stub_end - stub_base, Perm::READ | Perm::EXEC);
+    let resolver = TLSDESC_RESOLVER
+        .iter()
+        .flat_map(|i| i.to_le_bytes().to_vec())
+        .collect::<Vec<_>>();
+    vm.write(tlsdesc, &resolver);
+    let stubs = stubs
+        .into_iter()
+        .map(|(name, addr)| {
+            vm.write(addr, &dynamic::stub_code());
+            info!("Native function {} at stub {:#x}", name, addr);
+            (addr, natives.remove(name).unwrap())
+        })
+        .collect();
+
+    // the thread pointer and the TLS blocks follow the stubs, then the DTV in the layout of
+    // glibc: the number of modules, the generation, and the block of each module in 16-byte
+    // entries.  The TCB points to the generation
+    let tp = align_up(stub_end + PRE_TCB_SIZE, tls_align);
+    let dtv = align_up(tp + tls_size, 16);
+    let tls_end = page_align(dtv + (modules + 2) * 16);
+    if tls_end > GUEST_SIZE as u64 - STACK_SIZE {
+        return Err("TLS blocks do not fit in the guest address space".to_owned());
+    }
+    vm.map(stub_end, tls_end - stub_end, Perm::READ | Perm::WRITE);
+    vm.write(dtv, &modules.to_le_bytes());
+    vm.write(tp, &(dtv + 16).to_le_bytes());
+    // the TLS images are copied after relocation
+    for (obj, (ph, block)) in objects.iter().zip(tls_segments.iter().zip(&tls)) {
+        if let (Some(ph), Some((module, offset))) = (ph, block) {
+            let image = obj.bias.wrapping_add(ph.p_vaddr) as usize;
+            let data = vm[image..image + ph.p_filesz as usize].to_vec();
+            vm.write(tp + offset, &data);
+            vm.write(dtv + 16 + module * 16, &(tp + offset).to_le_bytes());
+        }
+    }
+    startup.tp = tp;
+    info!(
+        "Thread pointer at {:#x}: {} TLS modules of {:#x} bytes",
+        tp,
+        modules,
+        tls_size - TCB_SIZE
+    );
+
+    // initializers of dependencies run first; the program runs its own
+    for (obj, elf) in objects.iter().zip(&elfs).skip(1).rev() {
+        if let Some(dynamic) = &elf.dynamic {
+            let info = &dynamic.info;
+            if info.init != 0 {
+                startup.init.push(obj.bias.wrapping_add(info.init));
+            }
+            let array = obj.bias.wrapping_add(info.init_array);
+            if !vm.all_mapped(array, info.init_arraysz as u64) {
+                return Err(format!("{}: bad init_array at {:#x}", obj.name, array));
+            }
+            for i in 0..info.init_arraysz as u64 / 8 {
+                let addr = (array + i * 8) as usize;
+                let func = u64::from_le_bytes(vm[addr..addr + 8].try_into().unwrap());
+                if func != 0 && func != !0 {
+                    startup.init.push(func);
+                }
+            }
+            debug!(
+                "{}: init {:#x}, {} init_array entries",
+                obj.name,
+                info.init,
+                info.init_arraysz / 8
+            );
+        }
+    }
+
+    Ok((startup, stubs, tls_end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // expected encodings from `llvm-mc -triple=aarch64 -show-encoding`
+
+    #[test]
+    fn encode_mov() {
+        let mut code = Vec::new();
+        emit_mov(&mut code, 0, 0xdef0_9abc_5678_1234);
+        // movz x0, #0x1234; movk x0, #0x5678, lsl #16; movk x0, #0x9abc, lsl #32;
+        // movk x0, #0xdef0, lsl #48
+        assert_eq!(code, [0xd282_4680, 0xf2aa_cf00, 0xf2d3_5780, 0xf2fb_de00]);
+
+        code.clear();
+        emit_mov(&mut code, 16, 0xffff_0000_0000_0000);
+        // movz x16, #0; movk x16, #0, lsl #16; movk x16, #0, lsl #32;
+        // movk x16, #0xffff, lsl #48
+        assert_eq!(code, [0xd280_0010, 0xf2a0_0010, 0xf2c0_0010, 0xf2ff_fff0]);
+    }
+
+    #[test]
+    fn encode_branch() {
+        let mut code = Vec::new();
+        emit_blr(&mut code, 16);
+        emit_br(&mut code, 17);
+        // blr x16; br x17
+        assert_eq!(code, [0xd63f_0200, 0xd61f_0220]);
+    }
+
+    #[test]
+    fn encode_str() {
+        let mut code = Vec::new();
+        emit_str(&mut code, 0, 17);
+        emit_str(&mut code, 30, 31);
+        // str x0, [x17]; str x30, [sp]
+        assert_eq!(code, [0xf900_0220, 0xf900_03fe]);
+    }
+
+    #[test]
+    fn encode_tls() {
+        let mut code = Vec::new();
+        emit_msr_tpidr(&mut code, 16);
+        // msr tpidr_el0, x16
+        assert_eq!(code, [0xd51b_d050]);
+        // ldr x0, [x0, #8]; ret
+        assert_eq!(TLSDESC_RESOLVER, [0xf940_0400, 0xd65f_03c0]);
+    }
+
+    #[test]
+    fn layout_tls() {
+        assert_eq!(tls_layout(&[]), (vec![], 16));
+        // the blocks follow the TCB in order, each at its own alignment
+        assert_eq!(
+            tls_layout(&[(0x24, 8), (0x10, 64), (3, 1), (8, 8)]),
+            (vec![0x10, 0x40, 0x50, 0x58], 0x60)
+        );
+    }
+}
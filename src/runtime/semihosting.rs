@@ -0,0 +1,85 @@
+// SPDX-FileCopyrightText: 2020 Pengcheng Xu <i@jsteward.moe>
+//
+// SPDX-License-Identifier: BSD-3-Clause
+
+use crate::guest::arm64::Arm64CpuState;
+use crate::runtime::*;
+use log::*;
+
+use std::convert::TryInto;
+use std::io::{self, Write};
+
+// semihosting operation numbers
+const SYS_WRITEC: u64 = 0x03;
+const SYS_WRITE0: u64 = 0x04;
+const SYS_EXIT: u64 = 0x18;
+const SYS_EXIT_EXTENDED: u64 = 0x20;
+
+// exit reason for normal termination of the application
+const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+
+// read `len` bytes of readable guest memory at `addr`
+fn read(guest_map: &GuestMap, addr: u64, len: u64) -> Option<Vec<u8>> {
+    let vm = guest_map.borrow();
+    if vm.check(addr, len, Perm::READ) {
+        Some(vm[addr as usize..(addr + len) as usize].to_vec())
+    } else {
+        None
+    }
+}
+
+// read a NUL-terminated guest string at `addr`, without the NUL
+fn read_string(guest_map: &GuestMap, addr: u64) -> Option<Vec<u8>> {
+    let mut ret = Vec::new();
+    let mut pos = addr;
+    loop {
+        let c = read(guest_map, pos, 1)?[0];
+        if c == 0 {
+            return Some(ret);
+        }
+        ret.push(c);
+        pos += 1;
+    }
+}
+
+/// Handle a semihosting call of the guest.
+///
+/// The operation number is read from `w0` and the parameter from `x1`; the result is written
+/// back into `x0`.  Only console output and exit are supported; other operations fail with -1.
+pub fn do_semihosting(guest_map: &GuestMap, cpu: &mut Arm64CpuState) {
+    let op = cpu.x[0] & 0xffff_ffff;
+    let param = cpu.x[1];
+
+    let output = match op {
+        SYS_WRITEC => read(guest_map, param, 1),
+        SYS_WRITE0 => read_string(guest_map, param),
+        SYS_EXIT | SYS_EXIT_EXTENDED => {
+            // the parameter block holds the reason and the exit code
+            let code = match read(guest_map, param, 16) {
+                Some(block) => {
+                    let reason = u64::from_le_bytes(block[..8].try_into().unwrap());
+                    let subcode = u64::from_le_bytes(block[8..].try_into().unwrap());
+                    if reason == ADP_STOPPED_APPLICATION_EXIT {
+                        subcode as i32
+                    } else {
+                        1
+                    }
+                }
+                None => 1,
+            };
+            info!("Semihosting exit with code {}", code);
+            process::exit(code);
+        }
+        _ => {
+            warn!("Unsupported semihosting operation {:#x}", op);
+            None
+        }
+    };
+    // the console of the guest is the standard error of the host
+    let ok = match output {
+        Some(s) => io::stderr().write_all(&s).is_ok(),
+        None => false,
+    };
+
+    cpu.x[0] = if ok { 0 } else { !0 };
+}
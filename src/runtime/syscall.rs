@@ -0,0 +1,490 @@
+// SPDX-FileCopyrightText: 2020 Pengcheng Xu <i@jsteward.moe>
+//
+// SPDX-License-Identifier: BSD-3-Clause
+
+use crate::guest::arm64::Arm64CpuState;
+use crate::runtime::*;
+use log::*;
+
+use libc::{c_char, c_int, c_void};
+use std::ffi::{CStr, CString, OsStr};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::Path;
+use std::{io, mem, process, ptr};
+
+/// Guest address where anonymous mappings without an address hint are placed.
+///
+/// The program break may grow up to this address.
+pub const MMAP_BASE: u64 = GUEST_SIZE as u64 / 2;
+
+// syscall numbers of the aarch64 Linux ABI (asm-generic)
+mod nr {
+    pub const FACCESSAT: u64 = 48;
+    pub const OPENAT: u64 = 56;
+    pub const CLOSE: u64 = 57;
+    pub const READ: u64 = 63;
+    pub const WRITE: u64 = 64;
+    pub const READV: u64 = 65;
+    pub const WRITEV: u64 = 66;
+    pub const PREAD64: u64 = 67;
+    pub const READLINKAT: u64 = 78;
+    pub const NEWFSTATAT: u64 = 79;
+    pub const FSTAT: u64 = 80;
+    pub const EXIT: u64 = 93;
+    pub const EXIT_GROUP: u64 = 94;
+    pub const SET_TID_ADDRESS: u64 = 96;
+    pub const CLOCK_GETTIME: u64 = 113;
+    pub const UNAME: u64 = 160;
+    pub const GETPID: u64 = 172;
+    pub const GETTID: u64 = 178;
+    pub const BRK: u64 = 214;
+    pub const MUNMAP: u64 = 215;
+    pub const MMAP: u64 = 222;
+    pub const MPROTECT: u64 = 226;
+    pub const GETRANDOM: u64 = 278;
+}
+
+// `open` flags that differ between aarch64 and other architectures
+const GUEST_O_FLAGS: [(u64, c_int); 4] = [
+    (0o40000, libc::O_DIRECTORY),
+    (0o100000, libc::O_NOFOLLOW),
+    (0o200000, libc::O_DIRECT),
+    (0o400000, libc::O_LARGEFILE),
+];
+
+// `mmap` flags are the same as on the host, except that this one may be missing from libc
+const MAP_FIXED_NOREPLACE: c_int = 0x100000;
+
+// maximum number of `iovec` entries accepted by Linux
+const IOV_MAX: u64 = 1024;
+
+/// `struct stat` of the aarch64 Linux ABI.
+#[repr(C)]
+struct GuestStat {
+    st_dev: u64,
+    st_ino: u64,
+    st_mode: u32,
+    st_nlink: u32,
+    st_uid: u32,
+    st_gid: u32,
+    st_rdev: u64,
+    __pad1: u64,
+    st_size: i64,
+    st_blksize: i32,
+    __pad2: i32,
+    st_blocks: i64,
+    st_atime: i64,
+    st_atime_nsec: u64,
+    st_mtime: i64,
+    st_mtime_nsec: u64,
+    st_ctime: i64,
+    st_ctime_nsec: u64,
+    __unused: [u32; 2],
+}
+
+impl From<&libc::stat> for GuestStat {
+    fn from(st: &libc::stat) -> Self {
+        Self {
+            st_dev: st.st_dev as u64,
+            st_ino: st.st_ino as u64,
+            st_mode: st.st_mode as u32,
+            st_nlink: st.st_nlink as u32,
+            st_uid: st.st_uid as u32,
+            st_gid: st.st_gid as u32,
+            st_rdev: st.st_rdev as u64,
+            __pad1: 0,
+            st_size: st.st_size as i64,
+            st_blksize: st.st_blksize as i32,
+            __pad2: 0,
+            st_blocks: st.st_blocks as i64,
+            st_atime: st.st_atime as i64,
+            st_atime_nsec: st.st_atime_nsec as u64,
+            st_mtime: st.st_mtime as i64,
+            st_mtime_nsec: st.st_mtime_nsec as u64,
+            st_ctime: st.st_ctime as i64,
+            st_ctime_nsec: st.st_ctime_nsec as u64,
+            __unused: [0; 2],
+        }
+    }
+}
+
+/// `struct iovec` of the aarch64 Linux ABI.
+#[repr(C)]
+struct GuestIovec {
+    iov_base: u64,
+    iov_len: u64,
+}
+
+// result of a syscall: the return value or an `errno`
+type SyscallResult = Result<u64, c_int>;
+
+// convert the return value of a libc function into a syscall result
+fn host_result(ret: i64) -> SyscallResult {
+    if ret < 0 {
+        Err(io::Error::last_os_error().raw_os_error().unwrap())
+    } else {
+        Ok(ret as u64)
+    }
+}
+
+fn open_flags(flags: u64) -> c_int {
+    let mut ret = flags;
+    for (guest, _) in GUEST_O_FLAGS.iter() {
+        ret &= !guest;
+    }
+    let mut ret = ret as c_int;
+    for (guest, host) in GUEST_O_FLAGS.iter() {
+        if flags & guest != 0 {
+            ret |= host;
+        }
+    }
+    ret
+}
+
+struct SyscallState {
+    guest_map: GuestMap,
+    brk_start: u64,
+    brk: u64,
+    mmap_base: u64,
+    mmap_limit: u64,
+}
+
+static mut SYSCALL_STATE: Option<SyscallState> = None;
+
+/// Initialize the syscall proxy for the loaded program.
+///
+/// `brk` is the initial program break of the guest, and `mmap_base` the address to start placing
+/// anonymous mappings at.
+pub fn init(guest_map: GuestMap, brk: u64, mmap_base: u64) {
+    unsafe {
+        SYSCALL_STATE = Some(SyscallState {
+            guest_map,
+            brk_start: brk,
+            brk,
+            mmap_base,
+            mmap_limit: GUEST_SIZE as u64 - STACK_SIZE,
+        });
+    }
+}
+
+/// Perform the syscall requested by the guest.
+///
+/// Per the aarch64 Linux ABI, the syscall number is read from `x8` and the arguments from
+/// `x0`-`x5`.  The result is written back into `x0`, with errors reported as negated `errno`.
+pub fn do_syscall(cpu: &mut Arm64CpuState) {
+    let state = unsafe {
+        SYSCALL_STATE
+            .as_mut()
+            .expect("syscall proxy not initialized")
+    };
+
+    let nr = cpu.x[8];
+    let args = cpu.x[..6].to_vec();
+
+    let ret = match unsafe { state.dispatch(nr, &args) } {
+        Ok(v) => v,
+        Err(e) => -(e as i64) as u64,
+    };
+    debug!("syscall {}({:#x?}) = {:#x}", nr, args, ret);
+
+    cpu.x[0] = ret;
+}
+
+impl SyscallState {
+    // host pointer for guest address `addr`, checking that `[addr, addr + len)` is in the guest
+    // address space
+    fn host_ptr(&self, addr: u64, len: u64) -> Result<*mut c_void, c_int> {
+        match addr.checked_add(len) {
+            Some(end) if end <= GUEST_SIZE as u64 => {
+                let base = self.guest_map.borrow_mut().as_mut_ptr();
+                Ok(unsafe { base.add(addr as usize) } as *mut c_void)
+            }
+            _ => Err(libc::EFAULT),
+        }
+    }
+
+    // host pointer for a guest buffer at `addr` of `len` bytes that allows `perm`
+    fn buf(&self, addr: u64, len: u64, perm: Perm) -> Result<*mut c_void, c_int> {
+        if self.guest_map.borrow().check(addr, len, perm) {
+            self.host_ptr(addr, len)
+        } else {
+            Err(libc::EFAULT)
+        }
+    }
+
+    // host pointer for a NUL-terminated guest string at `addr`
+    fn string(&self, addr: u64) -> Result<*const c_char, c_int> {
+        let vm = self.guest_map.borrow();
+        let mut pos = addr;
+        // make sure that every page scanned is readable
+        while vm.check(pos, 1, Perm::READ) {
+            let page_end = (pos / PAGE_SIZE + 1) * PAGE_SIZE;
+            if vm[pos as usize..page_end as usize].contains(&0) {
+                return Ok(unsafe { vm.as_ptr().add(addr as usize) } as *const c_char);
+            }
+            pos = page_end;
+        }
+        Err(libc::EFAULT)
+    }
+
+    // NUL-terminated guest path at `addr`, translated into the guest sysroot
+    fn path(&self, addr: u64) -> Result<CString, c_int> {
+        let path = unsafe { CStr::from_ptr(self.string(addr)?) };
+        let path = guest_path(Path::new(OsStr::from_bytes(path.to_bytes())));
+        Ok(CString::new(path.into_os_string().into_vec()).unwrap())
+    }
+
+    // copy a host value into guest memory at `addr`
+    fn write<T>(&self, addr: u64, val: T) -> SyscallResult {
+        let ptr = self.buf(addr, mem::size_of::<T>() as u64, Perm::WRITE)?;
+        unsafe { ptr::write_unaligned(ptr as *mut T, val) };
+        Ok(0)
+    }
+
+    // translate a guest `iovec` array into host ones, with the buffers allowing `perm`
+    fn iovec(&self, addr: u64, count: u64, perm: Perm) -> Result<Vec<libc::iovec>, c_int> {
+        if count > IOV_MAX {
+            return Err(libc::EINVAL);
+        }
+        let size = mem::size_of::<GuestIovec>() as u64;
+        (0..count)
+            .map(|i| {
+                let addr = i
+                    .checked_mul(size)
+                    .and_then(|off| off.checked_add(addr))
+                    .ok_or(libc::EFAULT)?;
+                let ptr = self.buf(addr, size, Perm::READ)?;
+                let iov = unsafe { ptr::read_unaligned(ptr as *const GuestIovec) };
+                Ok(libc::iovec {
+                    iov_base: self.buf(iov.iov_base, iov.iov_len, perm)?,
+                    iov_len: iov.iov_len as usize,
+                })
+            })
+            .collect()
+    }
+
+    unsafe fn dispatch(&mut self, nr: u64, a: &[u64]) -> SyscallResult {
+        match nr {
+            nr::FACCESSAT => {
+                let path = self.path(a[1])?;
+                host_result(libc::faccessat(a[0] as c_int, path.as_ptr(), a[2] as c_int, 0) as i64)
+            }
+            nr::OPENAT => {
+                let path = self.path(a[1])?;
+                let flags = open_flags(a[2]);
+                let mode = a[3] as libc::mode_t;
+                host_result(libc::openat(a[0] as c_int, path.as_ptr(), flags, mode) as i64)
+            }
+            nr::CLOSE => host_result(libc::close(a[0] as c_int) as i64),
+            nr::READ => {
+                let buf = self.buf(a[1], a[2], Perm::WRITE)?;
+                host_result(libc::read(a[0] as c_int, buf, a[2] as usize) as i64)
+            }
+            nr::WRITE => {
+                let buf = self.buf(a[1], a[2], Perm::READ)?;
+                host_result(libc::write(a[0] as c_int, buf, a[2] as usize) as i64)
+            }
+            nr::READV => {
+                let iov = self.iovec(a[1], a[2], Perm::WRITE)?;
+                host_result(libc::readv(a[0] as c_int, iov.as_ptr(), iov.len() as c_int) as i64)
+            }
+            nr::WRITEV => {
+                let iov = self.iovec(a[1], a[2], Perm::READ)?;
+                host_result(libc::writev(a[0] as c_int, iov.as_ptr(), iov.len() as c_int) as i64)
+            }
+            nr::PREAD64 => {
+                let buf = self.buf(a[1], a[2], Perm::WRITE)?;
+                let off = a[3] as libc::off_t;
+                host_result(libc::pread(a[0] as c_int, buf, a[2] as usize, off) as i64)
+            }
+            nr::READLINKAT => {
+                let path = self.path(a[1])?;
+                let buf = self.buf(a[2], a[3], Perm::WRITE)?;
+                let len = a[3] as usize;
+                host_result(libc::readlinkat(a[0] as c_int, path.as_ptr(), buf as _, len) as i64)
+            }
+            nr::NEWFSTATAT => {
+                let path = self.path(a[1])?;
+                let mut st = mem::zeroed();
+                let flags = a[3] as c_int;
+                host_result(libc::fstatat(a[0] as c_int, path.as_ptr(), &mut st, flags) as i64)?;
+                self.write(a[2], GuestStat::from(&st))
+            }
+            nr::FSTAT => {
+                let mut st = mem::zeroed();
+                host_result(libc::fstat(a[0] as c_int, &mut st) as i64)?;
+                self.write(a[1], GuestStat::from(&st))
+            }
+            nr::EXIT | nr::EXIT_GROUP => {
+                info!("Guest exited with code {}", a[0] as c_int);
+                process::exit(a[0] as c_int)
+            }
+            // the guest is single-threaded, the thread ID is the process ID
+            nr::SET_TID_ADDRESS | nr::GETPID | nr::GETTID => Ok(libc::getpid() as u64),
+            nr::CLOCK_GETTIME => {
+                let size = mem::size_of::<libc::timespec>() as u64;
+                let tp = self.buf(a[1], size, Perm::WRITE)?;
+                host_result(libc::clock_gettime(a[0] as libc::clockid_t, tp as _) as i64)
+            }
+            nr::UNAME => {
+                let mut uts: libc::utsname = mem::zeroed();
+                host_result(libc::uname(&mut uts) as i64)?;
+                // report the guest architecture
+                uts.machine = [0; 65];
+                for (d, s) in uts.machine.iter_mut().zip(b"aarch64") {
+                    *d = *s as c_char;
+                }
+                self.write(a[0], uts)
+            }
+            nr::BRK => Ok(self.do_brk(a[0])),
+            nr::MMAP => self.do_mmap(a[0], a[1], a[2], a[3], a[4], a[5]),
+            nr::MUNMAP => self.do_munmap(a[0], a[1]),
+            nr::MPROTECT => {
+                if a[0] % PAGE_SIZE != 0 {
+                    return Err(libc::EINVAL);
+                }
+                self.host_ptr(a[0], a[1])?;
+                if !self.guest_map.borrow().all_mapped(a[0], a[1]) {
+                    return Err(libc::ENOMEM);
+                }
+                let perm = Perm::from_prot(a[2] as c_int);
+                self.guest_map.borrow_mut().protect(a[0], a[1], perm);
+                Ok(0)
+            }
+            nr::GETRANDOM => {
+                let buf = self.buf(a[0], a[1], Perm::WRITE)?;
+                host_result(libc::syscall(libc::SYS_getrandom, buf, a[1], a[2]) as i64)
+            }
+            _ => {
+                warn!("Unimplemented syscall {}", nr);
+                Err(libc::ENOSYS)
+            }
+        }
+    }
+
+    unsafe fn do_brk(&mut self, addr: u64) -> u64 {
+        if addr >= self.brk_start && addr <= MMAP_BASE {
+            let mut vm = self.guest_map.borrow_mut();
+            let (old_end, new_end) = (page_align(self.brk), page_align(addr));
+            if addr < self.brk {
+                // released memory reads as zero when the break grows again
+                vm.zero(addr, self.brk - addr);
+                vm.protect(new_end, old_end - new_end, Perm::empty());
+            } else {
+                vm.protect(old_end, new_end - old_end, Perm::READ | Perm::WRITE);
+            }
+            self.brk = addr;
+        }
+        // failures are reported by returning the unchanged break
+        self.brk
+    }
+
+    unsafe fn do_mmap(
+        &mut self,
+        addr: u64,
+        len: u64,
+        prot: u64,
+        flags: u64,
+        fd: u64,
+        off: u64,
+    ) -> SyscallResult {
+        if len == 0 || off % PAGE_SIZE != 0 {
+            return Err(libc::EINVAL);
+        }
+        let len = checked_page_align(len).ok_or(libc::ENOMEM)?;
+        let flags = flags as c_int;
+
+        let addr = if flags & (libc::MAP_FIXED | MAP_FIXED_NOREPLACE) != 0 {
+            if addr % PAGE_SIZE != 0 {
+                return Err(libc::EINVAL);
+            }
+            addr
+        } else {
+            // address hints are ignored, unmapped ranges are reused
+            self.guest_map
+                .borrow()
+                .find_free(self.mmap_base, self.mmap_limit, len)
+                .ok_or(libc::ENOMEM)?
+        };
+        let ptr = self.host_ptr(addr, len)?;
+        if flags & MAP_FIXED_NOREPLACE != 0 && self.guest_map.borrow().any_mapped(addr, len) {
+            return Err(libc::EEXIST);
+        }
+
+        // the guest address space is already reserved, replace the pages in it
+        let flags = flags & !MAP_FIXED_NOREPLACE | libc::MAP_FIXED;
+        let perm = Perm::from_prot(prot as c_int);
+        let ret = libc::mmap(
+            ptr,
+            len as usize,
+            perm.host_prot(),
+            flags,
+            fd as c_int,
+            off as libc::off_t,
+        );
+        if ret == libc::MAP_FAILED {
+            host_result(-1)
+        } else {
+            self.guest_map.borrow_mut().protect(addr, len, perm);
+            Ok(addr)
+        }
+    }
+
+    unsafe fn do_munmap(&mut self, addr: u64, len: u64) -> SyscallResult {
+        if addr % PAGE_SIZE != 0 || len == 0 {
+            return Err(libc::EINVAL);
+        }
+        let len = checked_page_align(len).ok_or(libc::EINVAL)?;
+        let ptr = self.host_ptr(addr, len)?;
+
+        // keep the guest address space reserved with fresh zero pages
+        let ret = libc::mmap(
+            ptr,
+            len as usize,
+            libc::PROT_NONE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED,
+            -1,
+            0,
+        );
+        if ret == libc::MAP_FAILED {
+            host_result(-1)
+        } else {
+            self.guest_map
+                .borrow_mut()
+                .protect(addr, len, Perm::empty());
+            Ok(0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_flags_common() {
+        let flags =
+            libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_APPEND | libc::O_CLOEXEC;
+        assert_eq!(open_flags(flags as u64), flags);
+        assert_eq!(open_flags(0), libc::O_RDONLY);
+    }
+
+    #[test]
+    fn open_flags_aarch64() {
+        assert_eq!(open_flags(0o40000), libc::O_DIRECTORY);
+        assert_eq!(open_flags(0o100000), libc::O_NOFOLLOW);
+        assert_eq!(open_flags(0o200000), libc::O_DIRECT);
+        assert_eq!(open_flags(0o400000), libc::O_LARGEFILE);
+        assert_eq!(
+            open_flags(0o40000 | 0o100000 | libc::O_CLOEXEC as u64),
+            libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC
+        );
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn open_flags_x86_64() {
+        // O_DIRECTORY | O_NOFOLLOW | O_DIRECT on x86-64; the guest bits must not leak through
+        assert_eq!(open_flags(0o340000), 0o640000);
+    }
+}
@@ -0,0 +1,282 @@
+// SPDX-FileCopyrightText: 2020 Pengcheng Xu <i@jsteward.moe>
+//
+// SPDX-License-Identifier: BSD-3-Clause
+
+use crate::runtime::*;
+use bitflags::bitflags;
+use libc::c_int;
+
+use std::ops::{Deref, DerefMut, Range};
+
+bitflags! {
+    /// Access permissions of a guest page.
+    ///
+    /// A page without any permission is not mapped.
+    pub struct Perm: u8 {
+        const READ = 1;
+        const WRITE = 2;
+        const EXEC = 4;
+    }
+}
+
+impl Perm {
+    /// Convert from the `p_flags` field of an ELF program header.
+    pub fn from_elf(flags: u32) -> Self {
+        let mut ret = Perm::empty();
+        ret.set(Perm::READ, flags & goblin::elf::program_header::PF_R != 0);
+        ret.set(Perm::WRITE, flags & goblin::elf::program_header::PF_W != 0);
+        ret.set(Perm::EXEC, flags & goblin::elf::program_header::PF_X != 0);
+        ret
+    }
+
+    /// Convert from the `prot` argument of `mmap` and `mprotect`.
+    pub fn from_prot(prot: c_int) -> Self {
+        let mut ret = Perm::empty();
+        ret.set(Perm::READ, prot & libc::PROT_READ != 0);
+        ret.set(Perm::WRITE, prot & libc::PROT_WRITE != 0);
+        ret.set(Perm::EXEC, prot & libc::PROT_EXEC != 0);
+        ret
+    }
+
+    /// Protection of the host mapping for a guest page with this permission.
+    ///
+    /// Mapped pages are always readable, as the disassembler needs to fetch instructions from
+    /// execute-only pages; guest code is never executed directly on the host.
+    pub fn host_prot(self) -> c_int {
+        if self.is_empty() {
+            libc::PROT_NONE
+        } else if self.contains(Perm::WRITE) {
+            libc::PROT_READ | libc::PROT_WRITE
+        } else {
+            libc::PROT_READ
+        }
+    }
+}
+
+/// Guest virtual address space.
+///
+/// Dereferences to the host mapping of the whole address space.  The permissions of each guest
+/// page are recorded and enforced on the host mapping, such that guest accesses to unmapped
+/// pages and writes to read-only pages fault on the host.
+pub struct GuestVM {
+    mem: MmapMut,
+    perms: Vec<Perm>,
+    // guest code that changed since last taken
+    invalidated: Vec<Range<u64>>,
+}
+
+impl Deref for GuestVM {
+    type Target = MmapMut;
+
+    fn deref(&self) -> &Self::Target {
+        &self.mem
+    }
+}
+
+impl DerefMut for GuestVM {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.mem
+    }
+}
+
+// guest pages covering `[addr, addr + len)`
+fn pages(addr: u64, len: u64) -> Range<usize> {
+    let start = addr / PAGE_SIZE;
+    let end = (addr + len + PAGE_SIZE - 1) / PAGE_SIZE;
+    start as usize..end as usize
+}
+
+impl GuestVM {
+    /// Reserve a guest address space of [`GUEST_SIZE`](../constant.GUEST_SIZE.html) with no page
+    /// mapped.
+    pub fn new() -> Result<Self, String> {
+        let mem = MmapOptions::new()
+            .len(GUEST_SIZE)
+            .map_anon()
+            .map_err(|e| format!("failed to map guest virtual space: {}", e))?;
+        let num_pages = GUEST_SIZE / PAGE_SIZE as usize;
+        let mut ret = Self {
+            mem,
+            perms: vec![Perm::empty(); num_pages],
+            invalidated: Vec::new(),
+        };
+        ret.apply(0..num_pages);
+        Ok(ret)
+    }
+
+    /// Add `perm` to the pages covering `[addr, addr + len)`.
+    ///
+    /// Existing permissions are kept, as ELF segments with different permissions may share a page.
+    pub fn map(&mut self, addr: u64, len: u64, perm: Perm) {
+        let pages = pages(addr, len);
+        for p in &mut self.perms[pages.clone()] {
+            *p |= perm;
+        }
+        self.apply(pages);
+    }
+
+    /// Set the permissions of the pages covering `[addr, addr + len)` to `perm`.
+    ///
+    /// Setting empty permissions unmaps the pages.
+    pub fn protect(&mut self, addr: u64, len: u64, perm: Perm) {
+        let pages = pages(addr, len);
+        self.check_code(pages.clone());
+        for p in &mut self.perms[pages.clone()] {
+            *p = perm;
+        }
+        self.apply(pages);
+    }
+
+    /// Check if `perm` is allowed on all the pages covering `[addr, addr + len)`.
+    pub fn check(&self, addr: u64, len: u64, perm: Perm) -> bool {
+        match addr.checked_add(len) {
+            Some(end) if end <= GUEST_SIZE as u64 => self.perms[pages(addr, len)]
+                .iter()
+                .all(|p| p.contains(perm)),
+            _ => false,
+        }
+    }
+
+    /// Check if any of the pages covering `[addr, addr + len)` is mapped.
+    pub fn any_mapped(&self, addr: u64, len: u64) -> bool {
+        match addr.checked_add(len) {
+            Some(end) if end <= GUEST_SIZE as u64 => {
+                self.perms[pages(addr, len)].iter().any(|p| !p.is_empty())
+            }
+            _ => false,
+        }
+    }
+
+    /// Check if all the pages covering `[addr, addr + len)` are mapped.
+    pub fn all_mapped(&self, addr: u64, len: u64) -> bool {
+        match addr.checked_add(len) {
+            Some(end) if end <= GUEST_SIZE as u64 => {
+                self.perms[pages(addr, len)].iter().all(|p| !p.is_empty())
+            }
+            _ => false,
+        }
+    }
+
+    /// Find the lowest address in `[start, end)` with `len` bytes of unmapped pages.
+    ///
+    /// `start` and `len` must be page aligned.
+    pub fn find_free(&self, start: u64, end: u64, len: u64) -> Option<u64> {
+        let end = end.min(GUEST_SIZE as u64) / PAGE_SIZE;
+        let num_pages = len / PAGE_SIZE;
+        let mut addr = start / PAGE_SIZE;
+        while addr.checked_add(num_pages)? <= end {
+            let run = &self.perms[addr as usize..(addr + num_pages) as usize];
+            // restart after the last mapped page in the run
+            match run.iter().rposition(|p| !p.is_empty()) {
+                Some(i) => addr += i as u64 + 1,
+                None => return Some(addr * PAGE_SIZE),
+            }
+        }
+        None
+    }
+
+    /// Copy `data` into guest memory at `addr`, regardless of the page permissions.
+    pub fn write(&mut self, addr: u64, data: &[u8]) {
+        self.force_write(addr, data.len() as u64, |m| m.copy_from_slice(data));
+    }
+
+    /// Fill `[addr, addr + len)` in guest memory with zeroes, regardless of the page permissions.
+    pub fn zero(&mut self, addr: u64, len: u64) {
+        self.force_write(addr, len, |m| {
+            for b in m {
+                *b = 0;
+            }
+        });
+    }
+
+    /// Take the guest address ranges of executable pages that have been changed or unmapped since
+    /// the last call.
+    ///
+    /// Only changes through this structure are tracked; the guest writing to pages that are both
+    /// writable and executable is not detected.
+    pub fn take_invalidated(&mut self) -> Vec<Range<u64>> {
+        std::mem::take(&mut self.invalidated)
+    }
+
+    // record `pages` as invalidated if any of them is executable
+    fn check_code(&mut self, pages: Range<usize>) {
+        if self.perms[pages.clone()]
+            .iter()
+            .any(|p| p.contains(Perm::EXEC))
+        {
+            self.invalidated
+                .push(pages.start as u64 * PAGE_SIZE..pages.end as u64 * PAGE_SIZE);
+        }
+    }
+
+    fn force_write(&mut self, addr: u64, len: u64, f: impl FnOnce(&mut [u8])) {
+        if len == 0 {
+            return;
+        }
+        let pages = pages(addr, len);
+        self.check_code(pages.clone());
+        self.set_host_prot(pages.clone(), libc::PROT_READ | libc::PROT_WRITE);
+        f(&mut self.mem[addr as usize..(addr + len) as usize]);
+        self.apply(pages);
+    }
+
+    // enforce the recorded permissions of `pages` on the host mapping
+    fn apply(&mut self, pages: Range<usize>) {
+        // merge consecutive pages with the same protection into a single call
+        let mut start = pages.start;
+        while start < pages.end {
+            let prot = self.perms[start].host_prot();
+            let end = (start..pages.end)
+                .find(|&p| self.perms[p].host_prot() != prot)
+                .unwrap_or(pages.end);
+            self.set_host_prot(start..end, prot);
+            start = end;
+        }
+    }
+
+    fn set_host_prot(&mut self, pages: Range<usize>, prot: c_int) {
+        let page_size = PAGE_SIZE as usize;
+        let ret = unsafe {
+            libc::mprotect(
+                self.mem.as_mut_ptr().add(pages.start * page_size) as *mut _,
+                pages.len() * page_size,
+                prot,
+            )
+        };
+        assert_eq!(ret, 0, "failed to protect guest pages {:?}", pages);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_free_reuses_holes() {
+        let mut vm = GuestVM::new().unwrap();
+        let base = 0x10_0000;
+        let limit = base + 0x10 * PAGE_SIZE;
+        assert_eq!(vm.find_free(base, limit, 4 * PAGE_SIZE), Some(base));
+
+        vm.map(base, 8 * PAGE_SIZE, Perm::READ);
+        vm.protect(base + 2 * PAGE_SIZE, 3 * PAGE_SIZE, Perm::empty());
+        // the hole of three pages is too small, but fits smaller mappings
+        assert_eq!(
+            vm.find_free(base, limit, 4 * PAGE_SIZE),
+            Some(base + 8 * PAGE_SIZE)
+        );
+        assert_eq!(
+            vm.find_free(base, limit, 3 * PAGE_SIZE),
+            Some(base + 2 * PAGE_SIZE)
+        );
+        assert_eq!(vm.find_free(base, limit, 9 * PAGE_SIZE), None);
+        assert_eq!(
+            vm.find_free(base, limit, 8 * PAGE_SIZE),
+            Some(base + 8 * PAGE_SIZE)
+        );
+        assert_eq!(
+            vm.find_free(base, GUEST_SIZE as u64 + PAGE_SIZE, !0 << 12),
+            None
+        );
+    }
+}